@@ -0,0 +1,311 @@
+//! A small JSON HTTP API for inspecting and controlling pi-frame while it runs.
+//! Each request is served on its own thread, and every command is run on the
+//! main loop.
+
+use serde::Deserialize;
+use serde_json::{Value, json};
+use tiny_http::{Header, Method, Request, Response, Server};
+use tracing::{info, warn};
+
+use crate::{
+    control::{Command, CommandError, Commands, Output},
+    snapshots::SnapshotFormat,
+};
+
+/// `[api]`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ApiConfig {
+    /// The address to listen on, eg: `0.0.0.0:8080`
+    listen: String,
+    /// Require `Authorization: Bearer <token>` on every request
+    token: Option<String>,
+}
+
+pub fn start(config: &ApiConfig, commands: Commands) -> Result<(), Box<dyn std::error::Error>> {
+    let server = Server::http(&config.listen)
+        .map_err(|e| format!("can't listen on {}: {e}", config.listen))?;
+    info!("API listening on {}", config.listen);
+    if config.token.is_none() {
+        warn!("The API has no token, so anyone who can reach it can control pi-frame");
+    }
+    let token = config.token.clone();
+    std::thread::spawn(move || {
+        for request in server.incoming_requests() {
+            // Snapshots take a while, so don't keep other requests waiting
+            let (commands, token) = (commands.clone(), token.clone());
+            std::thread::spawn(move || handle(&commands, token.as_deref(), request));
+        }
+    });
+    Ok(())
+}
+
+/// The command for a request, or an error status and message.
+fn route(method: &Method, url: &str) -> Result<Command, (u16, String)> {
+    let (path, query) = url.split_once('?').unwrap_or((url, ""));
+    let query = |name: &str| {
+        query
+            .split('&')
+            .find_map(|pair| pair.strip_prefix(name)?.strip_prefix('='))
+    };
+    let seconds = || match query("seconds") {
+        None => Ok(None),
+        Some(seconds) => seconds.parse().map(Some).map_err(|_| {
+            (
+                400,
+                format!("expected a number of seconds, not {seconds:?}"),
+            )
+        }),
+    };
+    let segments = path.trim_matches('/').split('/').collect::<Vec<_>>();
+    let command = match (method, segments.as_slice()) {
+        (Method::Get, ["sources"]) => Command::Sources,
+        (Method::Get, ["metrics"]) => Command::Metrics,
+        (Method::Get, ["sources", id, "snapshot"]) => {
+            let format = match query("format") {
+                None | Some("jpeg" | "jpg") => SnapshotFormat::Jpeg,
+                Some("png") => SnapshotFormat::Png,
+                Some(format) => {
+                    return Err((
+                        400,
+                        format!("unknown format {format:?}, expected jpeg or png"),
+                    ));
+                }
+            };
+            Command::Snapshot {
+                source: id.to_string(),
+                format,
+            }
+        }
+        (Method::Post, ["sources", id, "restart"]) => Command::Restart(id.to_string()),
+        (Method::Post, ["sources", id, "record"]) => Command::Record {
+            source: id.to_string(),
+            seconds: seconds()?,
+        },
+        (Method::Post, ["sources", id, "talk"]) => Command::Talk {
+            source: Some(id.to_string()),
+            seconds: seconds()?,
+        },
+        (Method::Post, ["focus", id]) => Command::Focus(Some(id.to_string())),
+        (Method::Delete, ["focus"]) => Command::Focus(None),
+        (Method::Post, ["layout", name]) => Command::Layout(name.to_string()),
+        (Method::Post, ["display", "on"]) => Command::Display(Some(true)),
+        (Method::Post, ["display", "off"]) => Command::Display(Some(false)),
+        (Method::Post, ["display", "auto"]) => Command::Display(None),
+        (Method::Get, ["audio"]) => Command::Audio,
+        (Method::Post, ["audio", "volume", volume]) => {
+            let volume = volume
+                .parse()
+                .map_err(|_| (400, format!("expected a volume in percent, not {volume:?}")))?;
+            Command::Volume(volume)
+        }
+        (Method::Post, ["audio", "mute"]) => Command::Mute(true),
+        (Method::Post, ["audio", "unmute"]) => Command::Mute(false),
+        (Method::Get, ["intercom"]) => Command::Intercom,
+        (Method::Post, ["intercom"]) => Command::Talk {
+            source: None,
+            seconds: seconds()?,
+        },
+        (Method::Delete, ["intercom"]) => Command::StopTalking,
+        (Method::Post, ["reload"]) => Command::Reload,
+        (Method::Post, ["graph"]) => Command::DumpGraph,
+        _ => return Err((404, "not found".to_string())),
+    };
+    Ok(command)
+}
+
+/// Whether a request carries the token, if one is needed.
+fn authorized(request: &Request, token: Option<&str>) -> bool {
+    let Some(token) = token else {
+        return true;
+    };
+    request.headers().iter().any(|header| {
+        header.field.equiv("Authorization")
+            && header.value.as_str().strip_prefix("Bearer ") == Some(token)
+    })
+}
+
+fn handle(commands: &Commands, token: Option<&str>, request: Request) {
+    let error = |status, e: String| (status, Output::Json(json!({ "error": e })));
+    let route = if authorized(&request, token) {
+        route(request.method(), request.url())
+    } else {
+        Err((401, "missing or wrong token".to_string()))
+    };
+    let (status, output) = match route {
+        Err((status, e)) => error(status, e),
+        Ok(command) => match commands.call(command) {
+            Ok(Output::Json(Value::Null)) => (200, Output::Json(json!({ "ok": true }))),
+            Ok(output) => (200, output),
+            Err(e @ CommandError::NotFound(_)) => error(404, e.to_string()),
+            Err(e @ CommandError::TimedOut) => error(504, e.to_string()),
+            Err(e) => error(400, e.to_string()),
+        },
+    };
+    let (content_type, body) = match output {
+        Output::Json(value) => ("application/json", value.to_string().into_bytes()),
+        Output::Text(text) => ("text/plain; version=0.0.4", text.into_bytes()),
+        Output::Image {
+            format: SnapshotFormat::Jpeg,
+            data,
+        } => ("image/jpeg", data),
+        Output::Image {
+            format: SnapshotFormat::Png,
+            data,
+        } => ("image/png", data),
+    };
+    let content_type = Header::from_bytes("Content-Type", content_type).unwrap();
+    let response = Response::from_data(body)
+        .with_status_code(status)
+        .with_header(content_type);
+    if let Err(e) = request.respond(response) {
+        warn!("Failed to send API response: {e}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get(url: &str) -> Result<Command, (u16, String)> {
+        route(&Method::Get, url)
+    }
+
+    fn post(url: &str) -> Result<Command, (u16, String)> {
+        route(&Method::Post, url)
+    }
+
+    fn delete(url: &str) -> Result<Command, (u16, String)> {
+        route(&Method::Delete, url)
+    }
+
+    fn status(result: Result<Command, (u16, String)>) -> Option<u16> {
+        result.err().map(|(status, _)| status)
+    }
+
+    #[test]
+    fn routes_sources() {
+        assert!(matches!(get("/sources"), Ok(Command::Sources)));
+        assert!(matches!(get("/sources/"), Ok(Command::Sources)));
+        assert!(matches!(get("/metrics"), Ok(Command::Metrics)));
+        assert!(matches!(
+            post("/sources/door/restart"),
+            Ok(Command::Restart(source)) if source == "door"
+        ));
+        assert!(matches!(
+            post("/sources/door/record"),
+            Ok(Command::Record { source, seconds: None }) if source == "door"
+        ));
+        assert!(matches!(
+            post("/sources/door/talk?seconds=30"),
+            Ok(Command::Talk { source: Some(source), seconds: Some(30) }) if source == "door"
+        ));
+    }
+
+    #[test]
+    fn parses_seconds() {
+        assert!(matches!(
+            post("/sources/door/record?seconds=20"),
+            Ok(Command::Record {
+                seconds: Some(20),
+                ..
+            })
+        ));
+        assert!(matches!(
+            post("/sources/door/record?other=1&seconds=5"),
+            Ok(Command::Record {
+                seconds: Some(5),
+                ..
+            })
+        ));
+        assert!(matches!(
+            post("/sources/door/record?seconds="),
+            Err((400, _))
+        ));
+        assert!(matches!(
+            post("/sources/door/record?seconds=-1"),
+            Err((400, _))
+        ));
+        assert!(matches!(post("/intercom?seconds=soon"), Err((400, _))));
+    }
+
+    #[test]
+    fn parses_snapshot_format() {
+        let format = |url| match get(url) {
+            Ok(Command::Snapshot { source, format }) if source == "door" => Some(format),
+            _ => None,
+        };
+        assert!(matches!(
+            format("/sources/door/snapshot"),
+            Some(SnapshotFormat::Jpeg)
+        ));
+        assert!(matches!(
+            format("/sources/door/snapshot?format=jpg"),
+            Some(SnapshotFormat::Jpeg)
+        ));
+        assert!(matches!(
+            format("/sources/door/snapshot?format=png"),
+            Some(SnapshotFormat::Png)
+        ));
+        assert_eq!(status(get("/sources/door/snapshot?format=gif")), Some(400));
+    }
+
+    #[test]
+    fn routes_display_and_layout() {
+        assert!(matches!(
+            post("/focus/door"),
+            Ok(Command::Focus(Some(source))) if source == "door"
+        ));
+        assert!(matches!(delete("/focus"), Ok(Command::Focus(None))));
+        assert!(matches!(
+            post("/layout/2x2"),
+            Ok(Command::Layout(layout)) if layout == "2x2"
+        ));
+        assert!(matches!(
+            post("/display/on"),
+            Ok(Command::Display(Some(true)))
+        ));
+        assert!(matches!(
+            post("/display/off"),
+            Ok(Command::Display(Some(false)))
+        ));
+        assert!(matches!(post("/display/auto"), Ok(Command::Display(None))));
+        assert!(matches!(post("/reload"), Ok(Command::Reload)));
+        assert!(matches!(post("/graph"), Ok(Command::DumpGraph)));
+    }
+
+    #[test]
+    fn routes_audio_and_intercom() {
+        assert!(matches!(get("/audio"), Ok(Command::Audio)));
+        assert!(matches!(post("/audio/volume/40"), Ok(Command::Volume(40))));
+        assert_eq!(status(post("/audio/volume/loud")), Some(400));
+        assert_eq!(status(post("/audio/volume/-10")), Some(400));
+        assert!(matches!(post("/audio/mute"), Ok(Command::Mute(true))));
+        assert!(matches!(post("/audio/unmute"), Ok(Command::Mute(false))));
+        assert!(matches!(get("/intercom"), Ok(Command::Intercom)));
+        assert!(matches!(
+            post("/intercom"),
+            Ok(Command::Talk {
+                source: None,
+                seconds: None
+            })
+        ));
+        assert!(matches!(delete("/intercom"), Ok(Command::StopTalking)));
+    }
+
+    #[test]
+    fn rejects_unknown_paths_and_methods() {
+        for (method, url) in [
+            (Method::Get, "/"),
+            (Method::Get, "/nothing"),
+            (Method::Get, "/sources/door"),
+            (Method::Get, "/sources/door/snapshot/extra"),
+            (Method::Post, "/sources"),
+            (Method::Get, "/reload"),
+            (Method::Delete, "/audio"),
+            (Method::Post, "/display/dim"),
+        ] {
+            assert_eq!(status(route(&method, url)), Some(404), "{method} {url}");
+        }
+    }
+}
@@ -0,0 +1,276 @@
+//! Playing sources' audio, with only one source audible at a time: the focused
+//! one if it has audio, otherwise the configured one. Every source's audio goes
+//! to one input-selector in front of the pipeline's only audio sink.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use gstreamer::prelude::*;
+use serde::Deserialize;
+use serde_json::{Value, json};
+use tracing::info;
+
+/// `[audio]`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AudioConfig {
+    sink: AudioSink,
+    /// The ALSA or PulseAudio device, rather than the default one
+    device: Option<String>,
+    /// The source to hear while no source with audio is focused
+    source: Option<String>,
+    /// Percent, at startup
+    volume: u32,
+}
+
+impl Default for AudioConfig {
+    fn default() -> Self {
+        Self {
+            sink: AudioSink::default(),
+            device: None,
+            source: None,
+            volume: 100,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AudioSink {
+    /// Whichever of PulseAudio or ALSA is available
+    #[default]
+    Auto,
+    Alsa,
+    Pulse,
+}
+
+/// Decides which source is audible, and how loud. Cheap to clone, and usable
+/// from any thread. It outlives the pipeline, so the volume survives it being
+/// rebuilt.
+#[derive(Debug, Clone)]
+pub struct Mixer(Arc<Mutex<Inner>>);
+
+#[derive(Debug)]
+struct Inner {
+    config: AudioConfig,
+    volume: u32,
+    muted: bool,
+    focused: Option<String>,
+    /// The pipeline's one audio output, once it's been built
+    output: Option<Output>,
+    /// Each source's audio, once its stream has appeared
+    sources: HashMap<String, SourceAudio>,
+}
+
+/// The bin that plays whichever source the selector has chosen
+#[derive(Debug)]
+struct Output {
+    bin: glib::WeakRef<gstreamer::Bin>,
+    selector: glib::WeakRef<gstreamer::Element>,
+    volume: glib::WeakRef<gstreamer::Element>,
+}
+
+/// A source's way into the output: the selector's pad, and the output bin's
+/// ghost of it. Buffers reaching inactive pads are dropped.
+#[derive(Debug)]
+struct SourceAudio {
+    pad: glib::WeakRef<gstreamer::Pad>,
+    ghost: glib::WeakRef<gstreamer::GhostPad>,
+}
+
+impl Inner {
+    /// The focused source if it's playing audio, otherwise the configured one
+    fn audible(&self) -> Option<&str> {
+        self.focused
+            .as_deref()
+            .filter(|focused| self.sources.contains_key(*focused))
+            .or(self.config.source.as_deref())
+    }
+
+    fn apply(&mut self) {
+        self.sources
+            .retain(|_, source| source.pad.upgrade().is_some());
+        let Some(output) = &self.output else {
+            return;
+        };
+        let audible = self.audible();
+        let pad = audible
+            .and_then(|audible| self.sources.get(audible))
+            .and_then(|source| source.pad.upgrade());
+        if let Some(volume) = output.volume.upgrade() {
+            volume.set_property("volume", self.volume as f64 / 100.0);
+            volume.set_property("mute", self.muted || pad.is_none());
+        }
+        if let (Some(selector), Some(pad)) = (output.selector.upgrade(), pad) {
+            selector.set_property("active-pad", &pad);
+        }
+    }
+
+    /// Unlink a source from the selector, and give its pad back.
+    fn release(&mut self, source: &str) {
+        let Some(audio) = self.sources.remove(source) else {
+            return;
+        };
+        let selector = self
+            .output
+            .as_ref()
+            .and_then(|output| output.selector.upgrade());
+        let bin = self.output.as_ref().and_then(|output| output.bin.upgrade());
+        if let (Some(ghost), Some(bin)) = (audio.ghost.upgrade(), bin) {
+            let _ = ghost.set_active(false);
+            let _ = bin.remove_pad(&ghost);
+        }
+        if let (Some(pad), Some(selector)) = (audio.pad.upgrade(), selector) {
+            selector.release_request_pad(&pad);
+        }
+    }
+}
+
+impl Mixer {
+    pub fn new(config: &AudioConfig) -> Self {
+        Self(Arc::new(Mutex::new(Inner {
+            config: config.clone(),
+            volume: config.volume.min(100),
+            muted: false,
+            focused: None,
+            output: None,
+            sources: HashMap::new(),
+        })))
+    }
+
+    /// Use a new config's sink and default source. The volume is left as it is.
+    pub fn configure(&self, config: &AudioConfig) {
+        let mut inner = self.0.lock().unwrap();
+        inner.config = config.clone();
+        inner.apply();
+    }
+
+    /// Add the pipeline's audio output, the only one that opens the sink. Each
+    /// source's audio is connected to it with `add`.
+    pub fn build_output(
+        &self,
+        pipeline: &gstreamer::Pipeline,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut inner = self.0.lock().unwrap();
+        let device = inner
+            .config
+            .device
+            .as_ref()
+            .map(|device| format!("device={device:?}"))
+            .unwrap_or_default();
+        // Played as it arrives, like the video
+        let sink = match inner.config.sink {
+            AudioSink::Auto => "autoaudiosink sync=false".to_string(),
+            AudioSink::Alsa => format!("alsasink {device} sync=false async=false"),
+            AudioSink::Pulse => format!("pulsesink {device} sync=false async=false"),
+        };
+        let bin = gstreamer::parse::bin_from_description_with_name(
+            &format!(
+                "input-selector name=selector sync-streams=false
+                    ! audioconvert ! audioresample
+                    ! volume name=volume mute=true
+                    ! {sink}"
+            ),
+            false,
+            "audio",
+        )?;
+        // The sink can't preroll until a source's audio arrives, if one ever
+        // does, and the video mustn't wait for it
+        bin.set_property("async-handling", true);
+        pipeline.add(&bin)?;
+        inner.output = Some(Output {
+            selector: bin.by_name("selector").ok_or("no selector")?.downgrade(),
+            volume: bin.by_name("volume").ok_or("no volume")?.downgrade(),
+            bin: bin.downgrade(),
+        });
+        // Sources from an earlier pipeline went with it
+        inner.sources.clear();
+        inner.apply();
+        Ok(())
+    }
+
+    /// The pipeline description for decoding a source's audio, starting from
+    /// RTP. The same caps come from every source, so switching between them
+    /// doesn't renegotiate the sink.
+    pub fn branch_description(&self) -> &'static str {
+        "queue leaky=downstream max-size-time=1000000000
+            ! decodebin ! audioconvert ! audioresample
+            ! audio/x-raw,rate=48000,channels=2"
+    }
+
+    /// Connect a source's decoded audio to the output, replacing any it had
+    /// before.
+    pub fn add(&self, source: &str, src: &gstreamer::Pad) -> Result<(), String> {
+        let mut inner = self.0.lock().unwrap();
+        inner.release(source);
+        let output = inner.output.as_ref().ok_or("there's no audio output")?;
+        let (Some(bin), Some(selector)) = (output.bin.upgrade(), output.selector.upgrade()) else {
+            return Err("the audio output has gone".to_string());
+        };
+        let pad = selector
+            .request_pad_simple("sink_%u")
+            .ok_or("no selector pad")?;
+        let ghost = gstreamer::GhostPad::builder_with_target(&pad)
+            .map_err(|e| e.to_string())?
+            .name(source)
+            .build();
+        ghost.set_active(true).map_err(|e| e.to_string())?;
+        bin.add_pad(&ghost).map_err(|e| e.to_string())?;
+        src.link(&ghost).map_err(|e| e.to_string())?;
+        inner.sources.insert(
+            source.to_string(),
+            SourceAudio {
+                pad: pad.downgrade(),
+                ghost: ghost.downgrade(),
+            },
+        );
+        inner.apply();
+        Ok(())
+    }
+
+    /// Disconnect a source's audio, eg: before it's restarted.
+    pub fn remove(&self, source: &str) {
+        let mut inner = self.0.lock().unwrap();
+        inner.release(source);
+        inner.apply();
+    }
+
+    /// Follow the focused source, or go back to the configured one with `None`.
+    pub fn focus(&self, source: Option<&str>) {
+        let mut inner = self.0.lock().unwrap();
+        inner.focused = source.map(str::to_string);
+        inner.apply();
+    }
+
+    /// Set the volume, in percent.
+    pub fn set_volume(&self, volume: u32) -> Result<(), String> {
+        if volume > 100 {
+            return Err(format!("volume must be 0 to 100, not {volume}"));
+        }
+        let mut inner = self.0.lock().unwrap();
+        inner.volume = volume;
+        inner.apply();
+        info!("Set volume to {volume}%");
+        Ok(())
+    }
+
+    pub fn set_muted(&self, muted: bool) {
+        let mut inner = self.0.lock().unwrap();
+        inner.muted = muted;
+        inner.apply();
+        let action = if muted { "Muted" } else { "Unmuted" };
+        info!("{action} audio");
+    }
+
+    /// Which source is audible, and how loud.
+    pub fn status(&self) -> Value {
+        let inner = self.0.lock().unwrap();
+        json!({
+            "source": inner.audible(),
+            "volume": inner.volume,
+            "muted": inner.muted,
+        })
+    }
+}
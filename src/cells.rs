@@ -0,0 +1,275 @@
+//! Changing the grid while it's running: showing one cell full-screen, and
+//! drawing borders around cells.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, atomic::Ordering},
+};
+
+use gstreamer::prelude::*;
+use tracing::info;
+
+use crate::{
+    CompositorPad, InstantiatedSource, RestartReason, SourceType, audio::Mixer, restart_source,
+    stream_for_resize,
+};
+
+/// Width of the border around a highlighted cell
+const BORDER: i32 = 4;
+/// Above every other cell, but below the widgets
+const FOCUS_ZORDER: u32 = u32::MAX - 2;
+
+/// A place on the screen, as `(x, y, width, height)`
+pub type Rect = (i32, i32, i32, i32);
+
+/// The cells of one pipeline. Cheap to clone, and usable from any thread.
+#[derive(Clone)]
+pub struct Cells(Arc<Mutex<Inner>>);
+
+struct Inner {
+    pipeline: glib::WeakRef<gstreamer::Pipeline>,
+    width: i32,
+    height: i32,
+    cells: HashMap<String, Cell>,
+    focused: Option<String>,
+    /// Bumped whenever the focus changes, so older unfocus timers are ignored
+    focus_generation: u64,
+    /// Follows the focus, so the focused source is the one heard
+    mixer: Mixer,
+}
+
+struct Cell {
+    source: InstantiatedSource,
+    pad: CompositorPad,
+    zorder: u32,
+    /// A solid colour input behind the cell, shown by shrinking the cell
+    border: Option<gstreamer::Pad>,
+    highlighted: bool,
+    highlight_generation: u64,
+}
+
+impl Cell {
+    fn place(&self, focused: bool, width: i32, height: i32) {
+        let (x, y, width, height, zorder) = if focused {
+            (0, 0, width, height, FOCUS_ZORDER)
+        } else {
+            let pad = &self.pad;
+            (pad.x, pad.y, pad.width, pad.height, self.zorder)
+        };
+        let inset = if self.highlighted && self.border.is_some() {
+            BORDER
+        } else {
+            0
+        };
+        let pad = &self.pad.pad;
+        pad.set_property("xpos", x + inset);
+        pad.set_property("ypos", y + inset);
+        pad.set_property("width", width - 2 * inset);
+        pad.set_property("height", height - 2 * inset);
+        pad.set_property("zorder", zorder);
+
+        if let Some(border) = &self.border {
+            border.set_property("xpos", x);
+            border.set_property("ypos", y);
+            border.set_property("width", width);
+            border.set_property("height", height);
+            border.set_property("zorder", if focused { zorder - 1 } else { 0 });
+            border.set_property("alpha", if self.highlighted { 1.0f64 } else { 0.0 });
+        }
+    }
+}
+
+impl Inner {
+    fn place(&self, name: &str) {
+        if let Some(cell) = self.cells.get(name) {
+            let focused = self.focused.as_deref() == Some(name);
+            cell.place(focused, self.width, self.height);
+        }
+    }
+
+    /// Pick the stream best suited to an RTSP source's new size. Returns what
+    /// to restart if that's a different one, to do once the lock is released.
+    fn resize_stream(&self, name: &str, fullscreen: bool) -> Option<Resize> {
+        let cell = self.cells.get(name)?;
+        let source = &cell.source;
+        let SourceType::Rtsp { rtsp, .. } = &source.source.source else {
+            return None;
+        };
+        if source.fullscreen.load(Ordering::Relaxed) == fullscreen {
+            return None;
+        }
+        let size = if fullscreen {
+            source.screen
+        } else {
+            (source.width, source.height)
+        };
+        let current = source.output_size();
+        let primary = source.selection.lock().unwrap().primary;
+        source.fullscreen.store(fullscreen, Ordering::Relaxed);
+        let primary = stream_for_resize(rtsp, primary, current, size)?;
+        let pipeline = self.pipeline.upgrade()?;
+        source.selection.lock().unwrap().set_primary(primary);
+        Some(Resize(pipeline, source.clone()))
+    }
+}
+
+/// A source to restart on a stream of a different size
+struct Resize(gstreamer::Pipeline, InstantiatedSource);
+
+impl Resize {
+    fn restart(self) {
+        let Resize(pipeline, source) = self;
+        restart_source(&pipeline, &source, RestartReason::Resize);
+    }
+}
+
+impl Cells {
+    pub fn new(pipeline: &gstreamer::Pipeline, width: usize, height: usize, mixer: Mixer) -> Self {
+        mixer.focus(None);
+        Self(Arc::new(Mutex::new(Inner {
+            pipeline: pipeline.downgrade(),
+            width: width as _,
+            height: height as _,
+            cells: HashMap::new(),
+            focused: None,
+            focus_generation: 0,
+            mixer,
+        })))
+    }
+
+    /// Add a source's cell, and lay it out.
+    pub fn add(
+        &self,
+        source: &InstantiatedSource,
+        pad: CompositorPad,
+        border: Option<gstreamer::Pad>,
+    ) {
+        let mut inner = self.0.lock().unwrap();
+        let cell = Cell {
+            source: source.clone(),
+            pad,
+            // Leave room underneath for the borders
+            zorder: source.index as u32 + 1,
+            border,
+            highlighted: false,
+            highlight_generation: 0,
+        };
+        cell.place(false, inner.width, inner.height);
+        inner.cells.insert(source.name.clone(), cell);
+    }
+
+    /// Each source's place in the grid.
+    pub fn geometry(&self) -> Vec<(String, Rect)> {
+        let inner = self.0.lock().unwrap();
+        let mut cells = inner
+            .cells
+            .iter()
+            .map(|(name, cell)| {
+                let pad = &cell.pad;
+                (name.clone(), (pad.x, pad.y, pad.width, pad.height))
+            })
+            .collect::<Vec<_>>();
+        cells.sort();
+        cells
+    }
+
+    /// Show a source full-screen, optionally going back to the grid after `hold`
+    /// seconds. Returns false if there's no such source.
+    pub fn focus(&self, name: &str, hold: Option<u64>) -> bool {
+        let mut inner = self.0.lock().unwrap();
+        if !inner.cells.contains_key(name) {
+            return false;
+        }
+        let mut resizes = vec![];
+        if inner.focused.as_deref() != Some(name) {
+            if let Some(previous) = inner.focused.replace(name.to_string()) {
+                inner.place(&previous);
+                resizes.extend(inner.resize_stream(&previous, false));
+            }
+            info!("Focusing source: {name}");
+            inner.place(name);
+            resizes.extend(inner.resize_stream(name, true));
+            inner.mixer.focus(Some(name));
+        }
+
+        inner.focus_generation += 1;
+        let generation = inner.focus_generation;
+        drop(inner);
+        resizes.into_iter().for_each(Resize::restart);
+        if let Some(hold) = hold {
+            let cells = self.clone();
+            glib::timeout_add_seconds_once(hold as _, move || {
+                if cells.0.lock().unwrap().focus_generation == generation {
+                    cells.unfocus();
+                }
+            });
+        }
+        true
+    }
+
+    /// Go back to the grid.
+    pub fn unfocus(&self) {
+        let resize = {
+            let mut inner = self.0.lock().unwrap();
+            inner.focus_generation += 1;
+            let Some(previous) = inner.focused.take() else {
+                return;
+            };
+            info!("Unfocusing source: {previous}");
+            inner.place(&previous);
+            inner.mixer.focus(None);
+            inner.resize_stream(&previous, false)
+        };
+        if let Some(resize) = resize {
+            resize.restart();
+        }
+    }
+
+    /// Draw a border around a source's cell for `hold` seconds, if it has one.
+    pub fn highlight(&self, name: &str, hold: u64) {
+        let mut inner = self.0.lock().unwrap();
+        let Some(cell) = inner.cells.get_mut(name) else {
+            return;
+        };
+        if cell.border.is_none() {
+            return;
+        }
+        cell.highlighted = true;
+        cell.highlight_generation += 1;
+        let generation = cell.highlight_generation;
+        inner.place(name);
+
+        let cells = self.clone();
+        let name = name.to_string();
+        glib::timeout_add_seconds_once(hold as _, move || {
+            let mut inner = cells.0.lock().unwrap();
+            let Some(cell) = inner.cells.get_mut(&name) else {
+                return;
+            };
+            if cell.highlight_generation == generation {
+                cell.highlighted = false;
+                inner.place(&name);
+            }
+        });
+    }
+
+    /// React to motion in a source, as configured by its `motion` table.
+    pub fn motion(&self, name: &str) {
+        let motion = {
+            let inner = self.0.lock().unwrap();
+            let Some(cell) = inner.cells.get(name) else {
+                return;
+            };
+            let Some(motion) = cell.source.source.motion.clone() else {
+                return;
+            };
+            motion
+        };
+        if motion.highlight.is_some() {
+            self.highlight(name, motion.hold);
+        }
+        if motion.focus {
+            self.focus(name, Some(motion.hold));
+        }
+    }
+}
@@ -0,0 +1,195 @@
+//! The clock drawn in the corner of the display. It's formatted here rather
+//! than by clockoverlay so it can be shown in any timezone.
+
+use std::ffi::CString;
+
+use gstreamer::prelude::*;
+use serde::Deserialize;
+
+use crate::labels::Position;
+
+/// `[display.time]`, either a strftime format or a table
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "ClockConfig")]
+pub struct Clock {
+    format: String,
+    timezone: Option<String>,
+    /// LC_TIME locale for day and month names, and `%p`, `%c`, `%x` and `%X`
+    locale: Option<String>,
+    position: Option<Position>,
+    font: String,
+    second: Option<SecondClock>,
+}
+
+/// Another clock shown underneath the first, eg: for a remote office
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SecondClock {
+    format: Option<String>,
+    timezone: Option<String>,
+    /// Shown before the time
+    label: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ClockConfig {
+    Format(String),
+    Table(ClockTable),
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ClockTable {
+    format: Option<String>,
+    timezone: Option<String>,
+    locale: Option<String>,
+    position: Option<Position>,
+    /// A Pango font description, eg: "Arial 8"
+    font: Option<String>,
+    second: Option<SecondClock>,
+}
+
+const DEFAULT_FORMAT: &str = "%H:%M";
+const DEFAULT_FONT: &str = "Arial 8";
+
+impl TryFrom<ClockConfig> for Clock {
+    type Error = String;
+
+    fn try_from(config: ClockConfig) -> Result<Self, Self::Error> {
+        let table = match config {
+            ClockConfig::Format(format) => ClockTable {
+                format: Some(format),
+                timezone: None,
+                locale: None,
+                position: None,
+                font: None,
+                second: None,
+            },
+            ClockConfig::Table(table) => table,
+        };
+        let format = table.format.unwrap_or_else(|| DEFAULT_FORMAT.to_string());
+        validate(&format, table.timezone.as_deref())?;
+        if let Some(second) = &table.second {
+            validate(
+                second.format.as_deref().unwrap_or(&format),
+                second.timezone.as_deref(),
+            )?;
+        }
+        Ok(Self {
+            format,
+            timezone: table.timezone,
+            locale: table.locale,
+            position: table.position,
+            font: table.font.unwrap_or_else(|| DEFAULT_FONT.to_string()),
+            second: table.second,
+        })
+    }
+}
+
+pub fn time_zone(timezone: Option<&str>) -> Result<glib::TimeZone, String> {
+    match timezone {
+        Some(timezone) => glib::TimeZone::from_identifier(Some(timezone))
+            .ok_or_else(|| format!("unknown timezone {timezone:?}")),
+        None => Ok(glib::TimeZone::local()),
+    }
+}
+
+/// Reject formats that glib can't render, rather than showing nothing.
+fn validate(format: &str, timezone: Option<&str>) -> Result<(), String> {
+    let now = glib::DateTime::now(&time_zone(timezone)?)
+        .map_err(|e| format!("can't get the current time: {e}"))?;
+    now.format(format)
+        .map_err(|_| format!("invalid time format {format:?}"))?;
+    Ok(())
+}
+
+pub fn format_now(format: &str, timezone: &glib::TimeZone) -> String {
+    glib::DateTime::now(timezone)
+        .and_then(|now| now.format(format))
+        .map(|text| text.to_string())
+        .unwrap_or_default()
+}
+
+impl Clock {
+    /// The configured LC_TIME locale, if any.
+    pub fn locale(&self) -> Option<&str> {
+        self.locale.as_deref()
+    }
+
+    /// Switch the process to the configured LC_TIME locale, if any. Only call
+    /// this at startup, before any other threads are running.
+    pub fn set_locale(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let Some(locale) = &self.locale else {
+            return Ok(());
+        };
+        let c_locale = CString::new(locale.as_str())?;
+        // SAFETY: setlocale isn't thread-safe, but this is only called from `run`
+        // before it starts any other threads.
+        let result = unsafe { libc::setlocale(libc::LC_TIME, c_locale.as_ptr()) };
+        if result.is_null() {
+            return Err(format!("locale {locale:?} is not installed").into());
+        }
+        Ok(())
+    }
+
+    /// The timezone the clock is shown in, for widgets to agree with it.
+    pub fn timezone(&self) -> glib::TimeZone {
+        // Checked when the config was loaded
+        time_zone(self.timezone.as_deref()).unwrap_or_else(|_| glib::TimeZone::local())
+    }
+
+    /// The textoverlay for the compositor's output, named `clock`.
+    pub fn description(&self) -> String {
+        let font = &self.font;
+        let position = match self.position {
+            Some(position) => {
+                let (halignment, valignment) = position.alignment();
+                format!(
+                    "halignment={halignment} valignment={valignment} line-alignment={halignment}"
+                )
+            }
+            None => "halignment=absolute valignment=absolute x-absolute=1 y-absolute=1 \
+                     line-alignment=right"
+                .to_string(),
+        };
+        format!(r#"textoverlay name=clock text="" font-desc={font:?} {position}"#)
+    }
+
+    /// Keep the clock's text up to date from the main loop, for as long as the
+    /// overlay exists.
+    pub fn update(&self, overlay: &gstreamer::Element) {
+        let timezone = self.timezone();
+        let format = self.format.clone();
+        let second = self.second.as_ref().map(|second| {
+            // Checked when the config was loaded
+            let timezone =
+                time_zone(second.timezone.as_deref()).unwrap_or_else(|_| glib::TimeZone::local());
+            let format = second.format.clone().unwrap_or_else(|| format.clone());
+            let label = second.label.clone();
+            (format, timezone, label)
+        });
+        let text = move || {
+            let mut text = format_now(&format, &timezone);
+            if let Some((format, timezone, label)) = &second {
+                text += "\n";
+                if let Some(label) = label {
+                    text += label;
+                    text += " ";
+                }
+                text += &format_now(format, timezone);
+            }
+            text
+        };
+
+        overlay.set_property("text", text());
+        let overlay = overlay.downgrade();
+        glib::timeout_add_seconds_local(1, move || {
+            let Some(overlay) = overlay.upgrade() else {
+                return glib::ControlFlow::Break;
+            };
+            overlay.set_property("text", text());
+            glib::ControlFlow::Continue
+        });
+    }
+}
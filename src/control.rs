@@ -0,0 +1,401 @@
+//! Remote control. Commands can come from any thread, and are run on the main
+//! loop, where the pipeline is managed.
+
+use std::{
+    path::PathBuf,
+    sync::mpsc::{RecvTimeoutError, Sender},
+    time::Duration,
+};
+
+use futures_channel::mpsc::UnboundedSender;
+use futures_util::StreamExt;
+use serde_json::{Value, json};
+use tracing::{error, info};
+
+use crate::{
+    InstantiatedSource, RTSP_PREFIX, RestartReason, SourceType, load_config,
+    recordings::Recorder,
+    restart_source,
+    schedule::SchedulerHandle,
+    select_stream,
+    snapshots::{self, FrameGrabber, SnapshotFormat},
+    supervisor::SupervisorHandle,
+};
+
+/// How long to wait for the main loop to run a command
+const CALL_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long to wait for a source to produce a frame for a snapshot
+const SNAPSHOT_TIMEOUT: Duration = Duration::from_secs(5);
+const SNAPSHOT_QUALITY: u8 = 90;
+
+#[derive(Debug)]
+pub enum Command {
+    /// Describe each source and its state
+    Sources,
+    /// The pipeline's metrics, in the Prometheus text format
+    Metrics,
+    /// Restart a source by name
+    Restart(String),
+    /// Grab a frame from a source, from its highest resolution stream
+    Snapshot {
+        source: String,
+        format: SnapshotFormat,
+    },
+    /// Save a source's video to a file for `seconds`, or the configured length
+    Record {
+        source: String,
+        seconds: Option<u32>,
+    },
+    /// Show a source full-screen, or go back to the grid with `None`
+    Focus(Option<String>),
+    /// Switch to a named layout
+    Layout(String),
+    /// Turn the display on or off, or go back to the schedule with `None`
+    Display(Option<bool>),
+    /// Which source is heard, and how loud
+    Audio,
+    /// Set the volume, in percent
+    Volume(u32),
+    Mute(bool),
+    /// Who's being talked to
+    Intercom,
+    /// Send the microphone to a source's backchannel for `seconds`, or as long
+    /// as allowed. `None` talks to the configured source.
+    Talk {
+        source: Option<String>,
+        seconds: Option<u32>,
+    },
+    StopTalking,
+    /// Read the config file again and rebuild the pipeline
+    Reload,
+    /// Write the pipeline's graph to the `[graphs]` directory
+    DumpGraph,
+}
+
+#[derive(Debug)]
+pub enum CommandError {
+    /// There's no such source, layout, etc.
+    NotFound(String),
+    Failed(String),
+    /// The main loop didn't run the command in time
+    TimedOut,
+}
+
+impl std::fmt::Display for CommandError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CommandError::NotFound(e) | CommandError::Failed(e) => f.write_str(e),
+            CommandError::TimedOut => write!(
+                f,
+                "timed out after {}s waiting for the main loop",
+                CALL_TIMEOUT.as_secs()
+            ),
+        }
+    }
+}
+
+impl From<Box<dyn std::error::Error>> for CommandError {
+    fn from(e: Box<dyn std::error::Error>) -> Self {
+        CommandError::Failed(e.to_string())
+    }
+}
+
+/// What a command produces
+pub enum Output {
+    Json(Value),
+    /// Metrics, in the Prometheus text format
+    Text(String),
+    Image {
+        format: SnapshotFormat,
+        data: Vec<u8>,
+    },
+}
+
+pub type Reply = Result<Output, CommandError>;
+
+/// Where to send a command's result, once it's run
+struct Responder {
+    command: String,
+    reply: Option<Sender<Reply>>,
+}
+
+impl Responder {
+    fn send(self, result: Reply) {
+        match self.reply {
+            Some(reply) => {
+                let _ = reply.send(result);
+            }
+            None => {
+                if let Err(e) = result {
+                    error!("Command {} failed: {e}", self.command);
+                }
+            }
+        }
+    }
+}
+
+/// Sends commands to the main loop. Cheap to clone.
+#[derive(Clone)]
+pub struct Commands(UnboundedSender<(Command, Option<Sender<Reply>>)>);
+
+impl Commands {
+    /// Send a command without waiting for it to run. Returns false once the
+    /// main loop has gone away.
+    pub fn send(&self, command: Command) -> bool {
+        self.0.unbounded_send((command, None)).is_ok()
+    }
+
+    /// Run a command and wait for its result.
+    pub fn call(&self, command: Command) -> Reply {
+        let (tx, rx) = std::sync::mpsc::channel();
+        let shutting_down = || CommandError::Failed("pi-frame is shutting down".to_string());
+        self.0
+            .unbounded_send((command, Some(tx)))
+            .map_err(|_| shutting_down())?;
+        match rx.recv_timeout(CALL_TIMEOUT) {
+            Ok(reply) => reply,
+            Err(RecvTimeoutError::Timeout) => Err(CommandError::TimedOut),
+            Err(RecvTimeoutError::Disconnected) => Err(shutting_down()),
+        }
+    }
+}
+
+pub struct Controller {
+    pub config_file: PathBuf,
+    pub supervisor: SupervisorHandle,
+    pub scheduler: SchedulerHandle,
+}
+
+impl Controller {
+    /// Run commands sent with the returned `Commands`, for as long as the main
+    /// loop runs.
+    pub fn start(self) -> Commands {
+        let (tx, mut rx) = futures_channel::mpsc::unbounded();
+        // Sending wakes the main loop, so commands run as soon as they arrive
+        glib::MainContext::default().spawn_local(async move {
+            while let Some((command, reply)) = rx.next().await {
+                let responder = Responder {
+                    command: format!("{command:?}"),
+                    reply,
+                };
+                match command {
+                    Command::Snapshot { source, format } => {
+                        self.snapshot(&source, format, responder)
+                    }
+                    command => responder.send(self.run(&command)),
+                }
+            }
+        });
+        Commands(tx)
+    }
+
+    fn run(&self, command: &Command) -> Reply {
+        if !matches!(
+            command,
+            Command::Sources | Command::Metrics | Command::Audio | Command::Intercom
+        ) {
+            info!("Running command: {command:?}");
+        }
+        let not_running = || CommandError::Failed("the pipeline isn't running".to_string());
+        match command {
+            Command::Sources => {
+                let (pipeline, sources) = self.supervisor.sources().ok_or_else(not_running)?;
+                let mut sources = sources.values().collect::<Vec<_>>();
+                sources.sort_by_key(|source| (source.index, source.name.clone()));
+                let sources = sources
+                    .into_iter()
+                    .map(|source| source_json(&pipeline, source))
+                    .collect();
+                return Ok(Output::Json(Value::Array(sources)));
+            }
+            Command::Metrics => {
+                let metrics = self.supervisor.metrics().ok_or_else(not_running)?;
+                return Ok(Output::Text(metrics));
+            }
+            Command::Restart(name) => {
+                let (pipeline, sources) = self.supervisor.sources().ok_or_else(not_running)?;
+                let source = sources
+                    .get(name)
+                    .ok_or_else(|| CommandError::NotFound(format!("no source named {name:?}")))?;
+                restart_source(&pipeline, source, RestartReason::Manual);
+            }
+            Command::Record { source, seconds } => {
+                let (pipeline, sources) = self.supervisor.sources().ok_or_else(not_running)?;
+                let source = sources
+                    .get(source)
+                    .ok_or_else(|| CommandError::NotFound(format!("no source named {source:?}")))?;
+                let config = source.recordings.as_ref().ok_or_else(|| {
+                    CommandError::Failed("recordings aren't configured".to_string())
+                })?;
+                let recorder = Recorder::find(&pipeline, &source.name).ok_or_else(|| {
+                    CommandError::Failed(format!("{:?} isn't an RTSP source", source.name))
+                })?;
+                let path = recorder
+                    .start(config, *seconds)
+                    .map_err(CommandError::Failed)?;
+                return Ok(Output::Json(json!({ "path": path })));
+            }
+            Command::Focus(source) => {
+                let cells = self.supervisor.cells().ok_or_else(not_running)?;
+                match source {
+                    Some(source) => {
+                        if !cells.focus(source, None) {
+                            let e = format!("no source named {source:?}");
+                            return Err(CommandError::NotFound(e));
+                        }
+                    }
+                    None => cells.unfocus(),
+                }
+            }
+            Command::Layout(name) => self
+                .supervisor
+                .set_layout(name)
+                .map_err(CommandError::Failed)?,
+            Command::Display(on) => self.scheduler.force(*on),
+            Command::Audio | Command::Volume(_) | Command::Mute(_) => {
+                let mixer = self.supervisor.mixer().ok_or_else(not_running)?;
+                match command {
+                    Command::Volume(volume) => {
+                        mixer.set_volume(*volume).map_err(CommandError::Failed)?
+                    }
+                    Command::Mute(muted) => mixer.set_muted(*muted),
+                    _ => {}
+                }
+                return Ok(Output::Json(mixer.status()));
+            }
+            Command::Intercom | Command::Talk { .. } | Command::StopTalking => {
+                let intercom = self.supervisor.intercom().ok_or_else(not_running)?;
+                match command {
+                    Command::Talk { source, seconds } => {
+                        if let Some(source) = source {
+                            let (_, sources) = self.supervisor.sources().ok_or_else(not_running)?;
+                            if !sources.contains_key(source) {
+                                let e = format!("no source named {source:?}");
+                                return Err(CommandError::NotFound(e));
+                            }
+                        }
+                        intercom
+                            .talk(source.as_deref(), *seconds)
+                            .map_err(CommandError::Failed)?;
+                    }
+                    Command::StopTalking => intercom.stop(),
+                    _ => {}
+                }
+                return Ok(Output::Json(intercom.status()));
+            }
+            Command::DumpGraph => {
+                let path = self
+                    .supervisor
+                    .dump_graph("api")
+                    .map_err(CommandError::Failed)?;
+                return Ok(Output::Json(json!({ "path": path })));
+            }
+            Command::Reload => self
+                .supervisor
+                .replace_config(load_config(&self.config_file)?),
+            Command::Snapshot { .. } => unreachable!("snapshots are taken asynchronously"),
+        }
+        Ok(Output::Json(Value::Null))
+    }
+
+    /// Grab a frame from a source on another thread, as that means waiting for
+    /// the source to decode one.
+    fn snapshot(&self, source: &str, format: SnapshotFormat, responder: Responder) {
+        info!("Taking snapshot of source: {source}");
+        let grabber = self
+            .supervisor
+            .sources()
+            .ok_or_else(|| CommandError::Failed("the pipeline isn't running".to_string()))
+            .and_then(|(pipeline, sources)| {
+                let Some(source) = sources.get(source) else {
+                    return Err(CommandError::NotFound(format!(
+                        "no source named {source:?}"
+                    )));
+                };
+                let grabber = FrameGrabber::find(&pipeline, &source.name).ok_or_else(|| {
+                    let e = format!("{:?} isn't an RTSP source", source.name);
+                    CommandError::Failed(e)
+                })?;
+                // Cells are often decoded from a smaller stream, so connect to
+                // the largest one if that's not already it
+                let SourceType::Rtsp { rtsp, .. } = &source.source.source else {
+                    return Ok((grabber, None));
+                };
+                let largest = select_stream(rtsp, usize::MAX, usize::MAX);
+                let url = (largest != source.selection.lock().unwrap().index)
+                    .then(|| rtsp[largest].url.clone());
+                Ok((grabber, url))
+            });
+        let (grabber, url) = match grabber {
+            Ok(grabber) => grabber,
+            Err(e) => return responder.send(Err(e)),
+        };
+        std::thread::spawn(move || {
+            let image = match url {
+                Some(url) => snapshots::grab_url(&url, SNAPSHOT_TIMEOUT),
+                None => grabber.grab(SNAPSHOT_TIMEOUT),
+            };
+            let result = image
+                .and_then(|image| {
+                    format
+                        .encode(&image, SNAPSHOT_QUALITY)
+                        .map_err(|e| e.to_string())
+                })
+                .map(|data| Output::Image { format, data })
+                .map_err(CommandError::Failed);
+            responder.send(result);
+        });
+    }
+}
+
+/// Hide any username and password in a URL.
+fn redact(url: &str) -> String {
+    let Some((scheme, rest)) = url.split_once("://") else {
+        return url.to_string();
+    };
+    let host_end = rest.find('/').unwrap_or(rest.len());
+    match rest[..host_end].rfind('@') {
+        Some(at) => format!("{scheme}://***{}", &rest[at..]),
+        None => url.to_string(),
+    }
+}
+
+fn source_json(pipeline: &gstreamer::Pipeline, source: &InstantiatedSource) -> Value {
+    use gstreamer::prelude::*;
+
+    let config = match &source.source.source {
+        SourceType::Rtsp { rtsp, scale } => json!({
+            "type": "rtsp",
+            "urls": rtsp.iter().map(|stream| redact(&stream.url)).collect::<Vec<_>>(),
+            "current": source.selection.lock().unwrap().index,
+            "scale": format!("{scale:?}").to_lowercase(),
+        }),
+        SourceType::Videotestsrc { videotestsrc } => json!({
+            "type": "videotestsrc",
+            "pattern": videotestsrc,
+        }),
+        SourceType::Image { image, .. } => json!({
+            "type": "image",
+            "image": image,
+        }),
+    };
+
+    // The decoder's output for RTSP sources, otherwise whatever the source produces
+    let decoder = format!("{RTSP_PREFIX}{}_decoder", source.name);
+    let caps = pipeline
+        .by_name(&decoder)
+        .or_else(|| pipeline.by_name(&source.name))
+        .and_then(|element| element.static_pad("src"))
+        .and_then(|pad| pad.current_caps())
+        .map(|caps| caps.to_string());
+
+    let state = source.state.lock().unwrap();
+    json!({
+        "id": source.name,
+        "description": source.source.description,
+        "config": config,
+        "state": if state.on_fallback { "offline" } else { "ok" },
+        "restarts": state.restarts.values().sum::<u32>(),
+        "caps": caps,
+    })
+}
@@ -0,0 +1,459 @@
+//! Still images drawn straight to the framebuffer, outside of the GStreamer pipeline.
+
+use std::{
+    io::Read,
+    path::{Path, PathBuf},
+};
+
+use image::{DynamicImage, RgbImage, imageops::FilterType};
+
+#[derive(Debug, Clone, Copy)]
+pub struct Channel {
+    offset: u32,
+    length: u32,
+}
+
+impl From<&framebuffer::Bitfield> for Channel {
+    fn from(bitfield: &framebuffer::Bitfield) -> Self {
+        Self {
+            offset: bitfield.offset,
+            length: bitfield.length,
+        }
+    }
+}
+
+/// The layout of a single framebuffer pixel
+#[derive(Debug, Clone, Copy)]
+pub struct PixelFormat {
+    bits_per_pixel: u32,
+    red: Channel,
+    green: Channel,
+    blue: Channel,
+}
+
+impl PixelFormat {
+    pub fn from_screen_info(info: &framebuffer::VarScreeninfo) -> Self {
+        Self {
+            bits_per_pixel: info.bits_per_pixel,
+            red: (&info.red).into(),
+            green: (&info.green).into(),
+            blue: (&info.blue).into(),
+        }
+    }
+
+    /// The usual layout for a given colour depth, for when there's no
+    /// framebuffer to ask.
+    pub fn for_depth(bits_per_pixel: u32) -> Option<Self> {
+        let channel = |offset, length| Channel { offset, length };
+        let (red, green, blue) = match bits_per_pixel {
+            16 => (channel(11, 5), channel(5, 6), channel(0, 5)),
+            24 | 32 => (channel(16, 8), channel(8, 8), channel(0, 8)),
+            _ => return None,
+        };
+        Some(Self {
+            bits_per_pixel,
+            red,
+            green,
+            blue,
+        })
+    }
+
+    pub fn bytes_per_pixel(&self) -> usize {
+        self.bits_per_pixel.div_ceil(8) as _
+    }
+
+    fn decode(&self, value: u32) -> [u8; 3] {
+        fn channel(value: u32, channel: Channel) -> u8 {
+            let length = channel.length.min(8);
+            let bits = (value >> channel.offset) & ((1 << length) - 1);
+            // Replicate the high bits into the low bits so full intensity stays at 255
+            ((bits << (8 - length)) | (bits >> (2 * length).saturating_sub(8))) as u8
+        }
+        [
+            channel(value, self.red),
+            channel(value, self.green),
+            channel(value, self.blue),
+        ]
+    }
+
+    fn encode(&self, [r, g, b]: [u8; 3]) -> u32 {
+        fn channel(value: u8, channel: Channel) -> u32 {
+            let length = channel.length.min(8);
+            ((value as u32) >> (8 - length)) << channel.offset
+        }
+        channel(r, self.red) | channel(g, self.green) | channel(b, self.blue)
+    }
+}
+
+/// The size and pixel format of a framebuffer, or of a raw dump of one
+#[derive(Debug, Clone, Copy)]
+pub struct Geometry {
+    pub width: u32,
+    pub height: u32,
+    pub format: PixelFormat,
+}
+
+impl std::str::FromStr for Geometry {
+    type Err = String;
+
+    /// Parse `<width>x<height>x<depth>`, eg: `1280x800x16`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid framebuffer geometry {s:?}, expected eg: 1280x800x16");
+        let mut parts = s.split('x').map(|part| part.parse::<u32>().map_err(|_| invalid()));
+        let (Some(width), Some(height), Some(depth), None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err(invalid());
+        };
+        let depth = depth?;
+        Ok(Self {
+            width: width?,
+            height: height?,
+            format: PixelFormat::for_depth(depth)
+                .ok_or_else(|| format!("unsupported framebuffer depth {depth}"))?,
+        })
+    }
+}
+
+impl Geometry {
+    pub fn from_screen_info(info: &framebuffer::VarScreeninfo) -> Self {
+        Self {
+            width: info.xres,
+            height: info.yres,
+            format: PixelFormat::from_screen_info(info),
+        }
+    }
+}
+
+pub fn load(path: impl AsRef<Path>) -> image::ImageResult<DynamicImage> {
+    image::ImageReader::open(path)?.with_guessed_format()?.decode()
+}
+
+/// Scale an image to fit within `width`x`height`, preserving its aspect ratio
+/// and centering it on a black background.
+pub fn fit(image: &DynamicImage, width: u32, height: u32) -> RgbImage {
+    let scaled = image.resize(width, height, FilterType::Triangle).to_rgb8();
+    let mut output = RgbImage::new(width, height);
+    let x = (width - scaled.width()) / 2;
+    let y = (height - scaled.height()) / 2;
+    image::imageops::overlay(&mut output, &scaled, x as _, y as _);
+    output
+}
+
+/// Convert an image to raw framebuffer memory, with each row padded out to
+/// `line_length` bytes.
+pub fn to_raw(image: &RgbImage, format: &PixelFormat, line_length: usize) -> Vec<u8> {
+    let bytes_per_pixel = format.bytes_per_pixel();
+    let line_length = line_length.max(image.width() as usize * bytes_per_pixel);
+    let mut raw = vec![0; line_length * image.height() as usize];
+    for (row, line) in image.rows().zip(raw.chunks_exact_mut(line_length)) {
+        for (pixel, out) in row.zip(line.chunks_exact_mut(bytes_per_pixel)) {
+            let value = format.encode(pixel.0).to_le_bytes();
+            out.copy_from_slice(&value[..bytes_per_pixel]);
+        }
+    }
+    raw
+}
+
+/// Convert raw framebuffer memory back into an image. Returns `None` if the
+/// buffer isn't the right size for the geometry.
+pub fn from_raw(raw: &[u8], geometry: &Geometry) -> Option<RgbImage> {
+    let line_length = geometry.width as usize * geometry.format.bytes_per_pixel();
+    if raw.len() != line_length * geometry.height as usize {
+        return None;
+    }
+    from_raw_lines(raw, geometry, line_length)
+}
+
+/// Like `from_raw`, for memory with each row padded out to `line_length` bytes.
+fn from_raw_lines(raw: &[u8], geometry: &Geometry, line_length: usize) -> Option<RgbImage> {
+    let bytes_per_pixel = geometry.format.bytes_per_pixel();
+    let row = geometry.width as usize * bytes_per_pixel;
+    if bytes_per_pixel > 4 || line_length < row || geometry.height == 0 {
+        return None;
+    }
+    if raw.len() < line_length * (geometry.height as usize - 1) + row {
+        return None;
+    }
+    Some(RgbImage::from_fn(geometry.width, geometry.height, |x, y| {
+        let offset = y as usize * line_length + x as usize * bytes_per_pixel;
+        let mut value = [0; 4];
+        value[..bytes_per_pixel].copy_from_slice(&raw[offset..offset + bytes_per_pixel]);
+        image::Rgb(geometry.format.decode(u32::from_le_bytes(value)))
+    }))
+}
+
+/// Read the visible area of the framebuffer, which may be panned within a
+/// larger virtual one, eg: when it's double buffered.
+pub fn read(framebuffer: &framebuffer::Framebuffer) -> Result<RgbImage, String> {
+    // The offsets change whenever the display is panned, so ask every time
+    let info = framebuffer::Framebuffer::get_var_screeninfo(&framebuffer.device)
+        .map_err(|e| format!("can't get screen info: {}", e.details))?;
+    let geometry = Geometry::from_screen_info(&info);
+    let line_length = framebuffer.fix_screen_info.line_length as usize;
+    read_visible(
+        &framebuffer.frame,
+        &geometry,
+        line_length,
+        (info.xoffset, info.yoffset),
+    )
+}
+
+/// Read the `geometry`-sized area at `(xoffset, yoffset)` of a framebuffer's
+/// memory, with rows of `line_length` bytes.
+fn read_visible(
+    frame: &[u8],
+    geometry: &Geometry,
+    line_length: usize,
+    (xoffset, yoffset): (u32, u32),
+) -> Result<RgbImage, String> {
+    let offset =
+        yoffset as usize * line_length + xoffset as usize * geometry.format.bytes_per_pixel();
+    let raw = frame
+        .get(offset..)
+        .ok_or("the visible area is outside of the framebuffer")?;
+    from_raw_lines(raw, geometry, line_length).ok_or_else(|| {
+        format!(
+            "can't read a {}x{}x{} framebuffer with {line_length} byte lines",
+            geometry.width, geometry.height, geometry.format.bits_per_pixel
+        )
+    })
+}
+
+/// Draw an image to the visible area of the framebuffer, scaled to fit.
+pub fn draw(framebuffer: &mut framebuffer::Framebuffer, image: &DynamicImage) {
+    let info = &framebuffer.var_screen_info;
+    let format = PixelFormat::from_screen_info(info);
+    let line_length = framebuffer.fix_screen_info.line_length as usize;
+    let image = fit(image, info.xres, info.yres);
+    draw_visible(
+        &mut framebuffer.frame,
+        &image,
+        &format,
+        line_length,
+        (info.xoffset, info.yoffset),
+    );
+}
+
+/// Draw an image at `(xoffset, yoffset)` of a framebuffer's memory, with rows
+/// of `line_length` bytes. Anything outside of the framebuffer is clipped.
+fn draw_visible(
+    frame: &mut [u8],
+    image: &RgbImage,
+    format: &PixelFormat,
+    line_length: usize,
+    (xoffset, yoffset): (u32, u32),
+) {
+    let bytes_per_pixel = format.bytes_per_pixel();
+    let row = image.width() as usize * bytes_per_pixel;
+    let raw = to_raw(image, format, row);
+    let start = yoffset as usize * line_length + xoffset as usize * bytes_per_pixel;
+    for (y, line) in raw.chunks_exact(row).enumerate() {
+        let offset = start + y * line_length;
+        let Some(out) = frame.get_mut(offset..) else {
+            break;
+        };
+        let len = row.min(out.len());
+        out[..len].copy_from_slice(&line[..len]);
+    }
+}
+
+/// Load either an image file, or a gzip'd raw framebuffer dump of the given
+/// geometry.
+fn load_any(path: &Path, raw_geometry: &Geometry) -> Result<DynamicImage, Box<dyn std::error::Error>> {
+    let bytes = std::fs::read(path)?;
+    if !bytes.starts_with(&[0x1f, 0x8b]) {
+        return Ok(image::load_from_memory(&bytes)?);
+    }
+
+    let mut raw = vec![];
+    flate2::read::GzDecoder::new(bytes.as_slice()).read_to_end(&mut raw)?;
+    let image = from_raw(&raw, raw_geometry).ok_or_else(|| {
+        format!(
+            "{} isn't the size of a {}x{}x{} framebuffer, pass --fb-geometry",
+            path.display(),
+            raw_geometry.width,
+            raw_geometry.height,
+            raw_geometry.format.bits_per_pixel,
+        )
+    })?;
+    Ok(image.into())
+}
+
+/// `pi-frame show <image> [--framebuffer <device>] [--fb-geometry <width>x<height>x<depth>]`
+///
+/// Raw dumps are assumed to match the framebuffer they're shown on, unless
+/// `--fb-geometry` says otherwise.
+pub fn show_command(args: impl IntoIterator<Item = String>) -> Result<(), Box<dyn std::error::Error>> {
+    const USAGE: &str = "Usage: pi-frame show <image> [--framebuffer <device>] [--fb-geometry <width>x<height>x<depth>]";
+
+    let mut input = None;
+    let mut device = PathBuf::from("/dev/fb0");
+    let mut raw_geometry = None;
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--framebuffer" => device = PathBuf::from(args.next().ok_or(USAGE)?),
+            "--fb-geometry" => raw_geometry = Some(args.next().ok_or(USAGE)?.parse::<Geometry>()?),
+            _ if input.is_none() && !arg.starts_with('-') => input = Some(PathBuf::from(arg)),
+            _ => return Err(USAGE.into()),
+        }
+    }
+    let input = input.ok_or(USAGE)?;
+
+    let mut framebuffer = framebuffer::Framebuffer::new(&device)?;
+    let raw_geometry =
+        raw_geometry.unwrap_or_else(|| Geometry::from_screen_info(&framebuffer.var_screen_info));
+    let image = load_any(&input, &raw_geometry)?;
+    draw(&mut framebuffer, &image);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_geometry() {
+        let geometry = "1280x800x16".parse::<Geometry>().unwrap();
+        assert_eq!((geometry.width, geometry.height), (1280, 800));
+        assert_eq!(geometry.format.bytes_per_pixel(), 2);
+        assert_eq!(
+            "640x480x32"
+                .parse::<Geometry>()
+                .unwrap()
+                .format
+                .bytes_per_pixel(),
+            4
+        );
+
+        for invalid in [
+            "",
+            "1280x800",
+            "1280x800x16x1",
+            "1280xx16",
+            "ax800x16",
+            "1280x800x12",
+        ] {
+            assert!(invalid.parse::<Geometry>().is_err(), "{invalid:?} parsed");
+        }
+    }
+
+    /// Colours every depth can represent exactly
+    fn test_image() -> RgbImage {
+        let colours = [
+            [0, 0, 0],
+            [255, 255, 255],
+            [255, 0, 0],
+            [0, 255, 0],
+            [0, 0, 255],
+        ];
+        RgbImage::from_fn(5, 3, |x, y| {
+            image::Rgb(colours[(x + y) as usize % colours.len()])
+        })
+    }
+
+    #[test]
+    fn round_trips_raw() {
+        let image = test_image();
+        for depth in [16, 24, 32] {
+            let geometry = Geometry {
+                width: image.width(),
+                height: image.height(),
+                format: PixelFormat::for_depth(depth).unwrap(),
+            };
+            let raw = to_raw(&image, &geometry.format, 0);
+            assert_eq!(raw.len(), 5 * 3 * geometry.format.bytes_per_pixel());
+            assert_eq!(from_raw(&raw, &geometry).unwrap(), image, "{depth} bpp");
+
+            // Rows padded out beyond the image's width
+            let line_length = 5 * geometry.format.bytes_per_pixel() + 6;
+            let raw = to_raw(&image, &geometry.format, line_length);
+            assert_eq!(raw.len(), line_length * 3);
+            let read = from_raw_lines(&raw, &geometry, line_length).unwrap();
+            assert_eq!(read, image, "{depth} bpp, padded");
+        }
+    }
+
+    #[test]
+    fn keeps_detail_at_24_bits() {
+        let image = RgbImage::from_pixel(2, 2, image::Rgb([12, 34, 56]));
+        let format = PixelFormat::for_depth(24).unwrap();
+        let geometry = Geometry {
+            width: 2,
+            height: 2,
+            format,
+        };
+        assert_eq!(
+            from_raw(&to_raw(&image, &format, 0), &geometry).unwrap(),
+            image
+        );
+    }
+
+    #[test]
+    fn rejects_wrongly_sized_raw() {
+        let geometry = "4x4x16".parse::<Geometry>().unwrap();
+        assert!(from_raw(&[0; 4 * 4 * 2 - 1], &geometry).is_none());
+        assert!(from_raw(&[0; 4 * 4 * 2], &geometry).is_some());
+        // eg: a dump of a bigger or deeper framebuffer
+        assert!(from_raw(&[0; 4 * 4 * 4], &geometry).is_none());
+    }
+
+    #[test]
+    fn reads_panned_framebuffer() {
+        let image = test_image();
+        for depth in [16, 32] {
+            let format = PixelFormat::for_depth(depth).unwrap();
+            // A double buffered virtual framebuffer, wider than the display, showing
+            // the image at (2, 4)
+            let mut virtual_image = RgbImage::new(8, 7);
+            image::imageops::replace(&mut virtual_image, &image, 2, 4);
+            let line_length = 8 * format.bytes_per_pixel() + 4;
+            let frame = to_raw(&virtual_image, &format, line_length);
+            let geometry = Geometry {
+                width: image.width(),
+                height: image.height(),
+                format,
+            };
+            let read = read_visible(&frame, &geometry, line_length, (2, 4)).unwrap();
+            assert_eq!(read, image, "{depth} bpp");
+            assert_ne!(
+                read_visible(&frame, &geometry, line_length, (0, 0)).unwrap(),
+                image
+            );
+        }
+    }
+
+    #[test]
+    fn draws_panned_framebuffer() {
+        let image = test_image();
+        for depth in [16, 32] {
+            let format = PixelFormat::for_depth(depth).unwrap();
+            let line_length = 8 * format.bytes_per_pixel() + 4;
+            let mut frame = vec![0; line_length * 7];
+            draw_visible(&mut frame, &image, &format, line_length, (2, 4));
+
+            let mut expected = RgbImage::new(8, 7);
+            image::imageops::replace(&mut expected, &image, 2, 4);
+            let virtual_geometry = Geometry {
+                width: 8,
+                height: 7,
+                format,
+            };
+            assert_eq!(
+                from_raw_lines(&frame, &virtual_geometry, line_length).unwrap(),
+                expected,
+                "{depth} bpp"
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_offsets_outside_the_framebuffer() {
+        let geometry = "4x4x16".parse::<Geometry>().unwrap();
+        let frame = [0; 8 * 8 * 2];
+        assert!(read_visible(&frame, &geometry, 8 * 2, (4, 4)).is_ok());
+        assert!(read_visible(&frame, &geometry, 8 * 2, (4, 5)).is_err());
+        assert!(read_visible(&frame, &geometry, 8 * 2, (0, 9)).is_err());
+    }
+}
@@ -0,0 +1,120 @@
+//! Dumping the pipeline as a Graphviz `.dot` file, for diagnosing problems.
+
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+use tracing::{debug, info, warn};
+
+/// `[graphs]`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GraphConfig {
+    /// Relative to the config file
+    directory: PathBuf,
+    /// How many dumps to keep, deleting the oldest
+    keep: usize,
+    /// Render each dump to SVG too, if Graphviz's `dot` is installed
+    svg: bool,
+    /// Dump the pipeline whenever a source is restarted
+    pub on_restart: bool,
+    /// Dump the pipeline before rebuilding it after a failure
+    pub on_error: bool,
+}
+
+impl Default for GraphConfig {
+    fn default() -> Self {
+        Self {
+            directory: PathBuf::from("graphs"),
+            keep: 20,
+            svg: true,
+            on_restart: true,
+            on_error: true,
+        }
+    }
+}
+
+impl GraphConfig {
+    pub fn resolve_paths(&mut self, config_dir: &Path) {
+        self.directory = config_dir.join(&self.directory);
+    }
+
+    /// Write the pipeline's graph, named after `reason`, and return its path.
+    /// Rendering and removing old dumps happen in the background.
+    pub fn dump(
+        &self,
+        pipeline: &gstreamer::Pipeline,
+        reason: &str,
+    ) -> Result<PathBuf, Box<dyn std::error::Error>> {
+        use gstreamer::prelude::*;
+
+        std::fs::create_dir_all(&self.directory)
+            .map_err(|e| format!("can't create {}: {e}", self.directory.display()))?;
+        // Sorts by time, to the microsecond so dumps in quick succession don't clash
+        let now = glib::DateTime::now_local()?.format("%Y%m%d-%H%M%S-%f")?;
+        let path = self.directory.join(format!("{now}-{reason}.dot"));
+        let graph = pipeline.debug_to_dot_data(gstreamer::DebugGraphDetails::all());
+        std::fs::write(&path, graph.as_bytes())
+            .map_err(|e| format!("can't write {}: {e}", path.display()))?;
+        info!("Wrote pipeline graph to {}", path.display());
+
+        let (dot, directory, keep, svg) =
+            (path.clone(), self.directory.clone(), self.keep, self.svg);
+        std::thread::spawn(move || {
+            if svg {
+                render(&dot);
+            }
+            prune(&directory, keep);
+        });
+        Ok(path)
+    }
+}
+
+fn render(dot: &Path) {
+    let svg = dot.with_extension("svg");
+    let result = std::process::Command::new("dot")
+        .arg("-Tsvg")
+        .arg("-o")
+        .arg(&svg)
+        .arg(dot)
+        .status();
+    match result {
+        Ok(status) if status.success() => debug!("Rendered {}", svg.display()),
+        Ok(status) => warn!(
+            "Failed to render {}: dot exited with {status}",
+            dot.display()
+        ),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            debug!("Graphviz isn't installed, not rendering {}", dot.display())
+        }
+        Err(e) => warn!("Failed to render {}: {e}", dot.display()),
+    }
+}
+
+/// Delete all but the newest `keep` dumps, along with their renderings.
+fn prune(directory: &Path, keep: usize) {
+    let entries = match std::fs::read_dir(directory) {
+        Ok(entries) => entries,
+        Err(e) => {
+            warn!("Can't list {}: {e}", directory.display());
+            return;
+        }
+    };
+    let mut dumps = entries
+        .filter_map(Result::ok)
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().is_some_and(|extension| extension == "dot"))
+        .collect::<Vec<_>>();
+    if dumps.len() <= keep {
+        return;
+    }
+    dumps.sort();
+    for dump in &dumps[..dumps.len() - keep] {
+        for path in [dump.clone(), dump.with_extension("svg")] {
+            match std::fs::remove_file(&path) {
+                Ok(()) => debug!("Removed old pipeline graph {}", path.display()),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => warn!("Can't remove {}: {e}", path.display()),
+            }
+        }
+    }
+}
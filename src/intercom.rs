@@ -0,0 +1,432 @@
+//! Push-to-talk for doorbells: capturing from a local microphone, and sending
+//! it to a camera over its RTSP ONVIF backchannel.
+
+use std::{
+    collections::HashMap,
+    io::Read,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use gstreamer::prelude::*;
+use gstreamer_app::{AppSink, AppSinkCallbacks};
+use serde::Deserialize;
+use serde_json::{Value, json};
+use tracing::{debug, error, info, warn};
+
+use crate::control::{Command, Commands};
+
+/// `EV_KEY` from linux/input-event-codes.h
+const EV_KEY: u16 = 0x01;
+
+/// `[intercom]`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IntercomConfig {
+    /// The pipeline description of what to capture, eg: `alsasrc device=hw:1,0`
+    microphone: String,
+    /// The source to talk to when none is given, eg: by the button
+    source: Option<String>,
+    /// The longest anyone can talk for in one go, in seconds
+    max_length: u32,
+    pub button: Option<ButtonConfig>,
+}
+
+impl Default for IntercomConfig {
+    fn default() -> Self {
+        Self {
+            microphone: "alsasrc".to_string(),
+            source: None,
+            max_length: 60,
+            button: None,
+        }
+    }
+}
+
+/// `[intercom.button]`, a key to hold down while talking
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ButtonConfig {
+    /// The input device, eg: `/dev/input/event0`
+    device: PathBuf,
+    /// The key's code, as shown by `evtest`
+    key: u16,
+}
+
+/// Talks to sources' backchannels. Cheap to clone, and usable from any
+/// thread. It outlives the pipeline, so what's configured survives it being
+/// rebuilt.
+#[derive(Debug, Clone)]
+pub struct Intercom(Arc<Mutex<Inner>>);
+
+#[derive(Debug)]
+struct Inner {
+    config: IntercomConfig,
+    /// Each source's backchannel, once its camera has offered one
+    backchannels: HashMap<String, Backchannel>,
+    talking: Option<Talking>,
+    /// Bumped whenever talking starts or stops, so anything left over from
+    /// earlier talking is ignored
+    generation: u64,
+}
+
+#[derive(Debug)]
+struct Backchannel {
+    rtspsrc: glib::WeakRef<gstreamer::Element>,
+    stream: u32,
+    caps: gstreamer::Caps,
+}
+
+#[derive(Debug)]
+struct Talking {
+    source: String,
+    pipeline: gstreamer::Pipeline,
+}
+
+impl Backchannel {
+    /// The pipeline description for encoding the microphone as the camera
+    /// asked for, ending at an appsink named `sink`.
+    fn description(&self, microphone: &str) -> Result<String, String> {
+        let structure = self.caps.structure(0).ok_or("backchannel has no caps")?;
+        let encoding = structure
+            .get::<&str>("encoding-name")
+            .map_err(|e| e.to_string())?;
+        let rate = structure.get::<i32>("clock-rate").unwrap_or(8000);
+        let payload = structure.get::<i32>("payload").map_err(|e| e.to_string())?;
+        let encoder = match encoding.to_uppercase().as_str() {
+            "PCMU" => "mulawenc ! rtppcmupay",
+            "PCMA" => "alawenc ! rtppcmapay",
+            _ => {
+                return Err(format!(
+                    "backchannel wants {encoding}, only PCMU and PCMA are supported"
+                ));
+            }
+        };
+        Ok(format!(
+            "{microphone} ! queue ! audioconvert ! audioresample
+                ! audio/x-raw,rate={rate},channels=1
+                ! {encoder} pt={payload}
+                ! appsink name=sink sync=false"
+        ))
+    }
+}
+
+impl Intercom {
+    pub fn new(config: &IntercomConfig) -> Self {
+        Self(Arc::new(Mutex::new(Inner {
+            config: config.clone(),
+            backchannels: HashMap::new(),
+            talking: None,
+            generation: 0,
+        })))
+    }
+
+    /// Use a new config. Anyone talking carries on.
+    pub fn configure(&self, config: &IntercomConfig) {
+        self.0.lock().unwrap().config = config.clone();
+    }
+
+    /// Set up a source's `rtspsrc` to offer its camera an ONVIF backchannel,
+    /// and remember the stream the camera sets up for it.
+    pub fn watch(&self, source: &str, rtspsrc: &gstreamer::Element) {
+        rtspsrc.set_property_from_str("backchannel", "onvif");
+        let intercom = self.clone();
+        let source = source.to_string();
+        rtspsrc.connect("select-stream", false, move |values| {
+            let rtspsrc = values[0].get::<gstreamer::Element>();
+            let stream = values[1].get::<u32>();
+            let caps = values[2].get::<gstreamer::Caps>();
+            if let (Ok(rtspsrc), Ok(stream), Ok(caps)) = (rtspsrc, stream, caps) {
+                // The backchannel is the stream the camera only receives
+                let sendonly = caps
+                    .structure(0)
+                    .is_some_and(|structure| structure.has_field("a-sendonly"));
+                if sendonly {
+                    debug!("Found backchannel for {source}: {caps}");
+                    let backchannel = Backchannel {
+                        rtspsrc: rtspsrc.downgrade(),
+                        stream,
+                        caps,
+                    };
+                    let mut inner = intercom.0.lock().unwrap();
+                    inner.backchannels.insert(source.clone(), backchannel);
+                }
+            }
+            Some(true.to_value())
+        });
+    }
+
+    /// Start sending the microphone to a source, or the configured one, for
+    /// up to `seconds`. Returns the source being talked to.
+    pub fn talk(&self, source: Option<&str>, seconds: Option<u32>) -> Result<String, String> {
+        // Starting and stopping pipelines can block, so the lock is only held
+        // to look things up, and to record the result
+        let (source, seconds, description, stream, rtspsrc, previous, generation) = {
+            let mut inner = self.0.lock().unwrap();
+            let source = source
+                .or(inner.config.source.as_deref())
+                .ok_or("no source given, and no intercom source configured")?
+                .to_string();
+            let max_length = inner.config.max_length;
+            let seconds = seconds.unwrap_or(max_length);
+            if seconds == 0 || seconds > max_length {
+                return Err(format!("talking must last 1 to {max_length} seconds"));
+            }
+            let backchannel = inner
+                .backchannels
+                .get(&source)
+                .ok_or_else(|| format!("{source:?} hasn't offered a backchannel"))?;
+            let rtspsrc = backchannel
+                .rtspsrc
+                .upgrade()
+                .ok_or_else(|| format!("{source:?} is restarting"))?;
+            let description = backchannel.description(&inner.config.microphone)?;
+            let stream = backchannel.stream;
+            inner.generation += 1;
+            let previous = inner.talking.take();
+            (
+                source,
+                seconds,
+                description,
+                stream,
+                rtspsrc,
+                previous,
+                inner.generation,
+            )
+        };
+        if let Some(previous) = previous {
+            stop(previous);
+        }
+
+        let pipeline = gstreamer::parse::launch(&description)
+            .map_err(|e| e.to_string())?
+            .downcast::<gstreamer::Pipeline>()
+            .map_err(|_| "microphone isn't a pipeline")?;
+        let sink = pipeline
+            .by_name("sink")
+            .and_then(|sink| sink.downcast::<AppSink>().ok())
+            .ok_or("no appsink")?;
+        let rtspsrc = rtspsrc.downgrade();
+        sink.set_callbacks(
+            AppSinkCallbacks::builder()
+                .new_sample(move |sink| {
+                    let sample = sink.pull_sample().map_err(|_| gstreamer::FlowError::Eos)?;
+                    let Some(rtspsrc) = rtspsrc.upgrade() else {
+                        return Err(gstreamer::FlowError::Flushing);
+                    };
+                    rtspsrc
+                        .emit_by_name::<gstreamer::FlowReturn>(
+                            "push-backchannel-sample",
+                            &[&stream, &sample],
+                        )
+                        .into_result()
+                })
+                .build(),
+        );
+        // Nothing watches this pipeline's bus, so stop talking from here when
+        // the microphone fails or runs out. The pipeline can't be stopped from
+        // its own streaming thread, so that's left to the main loop.
+        if let Some(bus) = pipeline.bus() {
+            let intercom = self.clone();
+            bus.set_sync_handler(move |_bus, message| {
+                let finished = match message.view() {
+                    gstreamer::MessageView::Error(e) => {
+                        error!("Intercom failed: {} ({:?})", e.error(), e.debug());
+                        true
+                    }
+                    gstreamer::MessageView::Eos(_) => true,
+                    _ => false,
+                };
+                if finished {
+                    let intercom = intercom.clone();
+                    glib::idle_add_once(move || intercom.stop_generation(generation));
+                }
+                gstreamer::BusSyncReply::Drop
+            });
+        }
+        if let Err(e) = pipeline.set_state(gstreamer::State::Playing) {
+            let _ = pipeline.set_state(gstreamer::State::Null);
+            return Err(format!("can't start the microphone: {e}"));
+        }
+
+        let talking = Talking {
+            source: source.clone(),
+            pipeline,
+        };
+        {
+            let mut inner = self.0.lock().unwrap();
+            if inner.generation == generation {
+                inner.talking = Some(talking);
+            } else {
+                // Stopped, or talking to someone else, while this was starting
+                drop(inner);
+                stop(talking);
+                return Err("stopped before talking started".to_string());
+            }
+        }
+        info!("Talking to {source} for up to {seconds}s");
+
+        let intercom = self.clone();
+        glib::timeout_add_local_once(Duration::from_secs(seconds.into()), move || {
+            intercom.stop_generation(generation);
+        });
+        Ok(source)
+    }
+
+    /// Stop sending the microphone anywhere.
+    pub fn stop(&self) {
+        let talking = {
+            let mut inner = self.0.lock().unwrap();
+            inner.generation += 1;
+            inner.talking.take()
+        };
+        if let Some(talking) = talking {
+            stop(talking);
+        }
+    }
+
+    /// Stop talking, if it's still what was started as `generation`.
+    fn stop_generation(&self, generation: u64) {
+        let talking = {
+            let mut inner = self.0.lock().unwrap();
+            if inner.generation != generation {
+                return;
+            }
+            inner.talking.take()
+        };
+        if let Some(talking) = talking {
+            stop(talking);
+        }
+    }
+
+    /// Who's being talked to.
+    pub fn status(&self) -> Value {
+        let inner = self.0.lock().unwrap();
+        let backchannels = {
+            let mut backchannels = inner
+                .backchannels
+                .iter()
+                .filter(|(_, backchannel)| backchannel.rtspsrc.upgrade().is_some())
+                .map(|(source, _)| source.clone())
+                .collect::<Vec<_>>();
+            backchannels.sort();
+            backchannels
+        };
+        json!({
+            "talking": inner.talking.as_ref().map(|talking| &talking.source),
+            "backchannels": backchannels,
+        })
+    }
+}
+
+fn stop(talking: Talking) {
+    info!("Stopped talking to {}", talking.source);
+    if let Err(e) = talking.pipeline.set_state(gstreamer::State::Null) {
+        warn!("Error stopping intercom: {e:?}");
+    }
+}
+
+/// Talk while the button's key is held down, for as long as the main loop
+/// runs. GPIO buttons can be made into keys with the `gpio-key` overlay.
+pub fn watch_button(
+    button: &ButtonConfig,
+    commands: Commands,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut device = std::fs::File::open(&button.device)
+        .map_err(|e| format!("can't open {}: {e}", button.device.display()))?;
+    let (path, key) = (button.device.clone(), button.key);
+    info!("Watching {} for the intercom button", path.display());
+    std::thread::Builder::new()
+        .name("intercom-button".to_string())
+        .spawn(move || {
+            let mut event = [0u8; size_of::<libc::input_event>()];
+            loop {
+                if let Err(e) = device.read_exact(&mut event) {
+                    error!("Failed to read {}: {e}", path.display());
+                    return;
+                }
+                // SAFETY: input_event is plain data, and the kernel writes whole events
+                let event =
+                    unsafe { std::ptr::read_unaligned(event.as_ptr().cast::<libc::input_event>()) };
+                if event.type_ != EV_KEY || event.code != key {
+                    continue;
+                }
+                let command = match event.value {
+                    1 => Command::Talk {
+                        source: None,
+                        seconds: None,
+                    },
+                    0 => Command::StopTalking,
+                    // Held down long enough to repeat
+                    _ => continue,
+                };
+                if !commands.send(command) {
+                    return;
+                }
+            }
+        })?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Run whatever's due on this thread's main loop, for `duration`.
+    fn run_main_loop(duration: Duration) {
+        let context = glib::MainContext::default();
+        let until = std::time::Instant::now() + duration;
+        while std::time::Instant::now() < until {
+            context.iteration(false);
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    /// Needs an RTSP server offering an ONVIF backchannel, eg: gst-rtsp-server's
+    /// `examples/test-onvif-backchannel`, at `PI_FRAME_BACKCHANNEL_URL` or
+    /// `rtsp://127.0.0.1:8554/test`. Run it with `cargo test -- --ignored`.
+    #[test]
+    #[ignore = "needs an RTSP server with an ONVIF backchannel"]
+    fn talks_over_backchannel() {
+        gstreamer::init().unwrap();
+        let url = std::env::var("PI_FRAME_BACKCHANNEL_URL")
+            .unwrap_or_else(|_| "rtsp://127.0.0.1:8554/test".to_string());
+        let intercom = Intercom::new(&IntercomConfig {
+            microphone: "audiotestsrc is-live=true".to_string(),
+            source: Some("door".to_string()),
+            max_length: 2,
+            button: None,
+        });
+        let camera =
+            gstreamer::parse::launch(&format!("rtspsrc name=src location={url:?} ! fakesink"))
+                .unwrap()
+                .downcast::<gstreamer::Pipeline>()
+                .unwrap();
+        intercom.watch("door", &camera.by_name("src").unwrap());
+        camera.set_state(gstreamer::State::Playing).unwrap();
+
+        run_main_loop(Duration::from_secs(3));
+        assert_eq!(intercom.status()["backchannels"], json!(["door"]));
+
+        assert_eq!(intercom.talk(None, None).unwrap(), "door");
+        run_main_loop(Duration::from_secs(1));
+        assert_eq!(intercom.status()["talking"], json!("door"));
+
+        // Stops by itself after max_length
+        run_main_loop(Duration::from_secs(2));
+        assert_eq!(intercom.status()["talking"], Value::Null);
+
+        // So does a microphone that runs out
+        let config = intercom.0.lock().unwrap().config.clone();
+        intercom.configure(&IntercomConfig {
+            microphone: "audiotestsrc num-buffers=10".to_string(),
+            ..config
+        });
+        intercom.talk(None, None).unwrap();
+        run_main_loop(Duration::from_secs(1));
+        assert_eq!(intercom.status()["talking"], Value::Null);
+
+        camera.set_state(gstreamer::State::Null).unwrap();
+    }
+}
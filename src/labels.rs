@@ -0,0 +1,193 @@
+//! Styling and visibility of the per-source text labels.
+
+use std::sync::{
+    Arc,
+    atomic::{AtomicU64, Ordering},
+};
+
+use gstreamer::prelude::*;
+use serde::Deserialize;
+
+/// An `#RRGGBB` or `#AARRGGBB` colour, stored as ARGB
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Color(pub u32);
+
+impl TryFrom<String> for Color {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        let invalid = || format!("invalid colour {s:?}, expected #RRGGBB or #AARRGGBB");
+        let hex = s.strip_prefix('#').ok_or_else(invalid)?;
+        let value = u32::from_str_radix(hex, 16).map_err(|_| invalid())?;
+        match hex.len() {
+            6 => Ok(Self(0xff000000 | value)),
+            8 => Ok(Self(value)),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Position {
+    TopLeft,
+    Top,
+    TopRight,
+    Left,
+    Center,
+    Right,
+    BottomLeft,
+    Bottom,
+    BottomRight,
+}
+
+impl Position {
+    /// The textoverlay `halignment` and `valignment` for this position
+    pub fn alignment(&self) -> (&'static str, &'static str) {
+        match self {
+            Position::TopLeft => ("left", "top"),
+            Position::Top => ("center", "top"),
+            Position::TopRight => ("right", "top"),
+            Position::Left => ("left", "center"),
+            Position::Center => ("center", "center"),
+            Position::Right => ("right", "center"),
+            Position::BottomLeft => ("left", "bottom"),
+            Position::Bottom => ("center", "bottom"),
+            Position::BottomRight => ("right", "bottom"),
+        }
+    }
+}
+
+/// Label settings, from `[display.labels]` or a source's `label` table. Anything
+/// left out of a source's settings comes from the display's.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LabelStyle {
+    font: Option<String>,
+    size: Option<u32>,
+    color: Option<Color>,
+    outline: Option<bool>,
+    shadow: Option<bool>,
+    /// Draw a shaded box behind the text
+    background: Option<bool>,
+    position: Option<Position>,
+    /// Hide the label this many seconds after it's shown
+    hide_after: Option<u64>,
+    /// Only show the label while the source is showing its fallback
+    only_on_fallback: Option<bool>,
+    /// Never show the label
+    hidden: Option<bool>,
+}
+
+impl LabelStyle {
+    /// Fill in anything unset here from `defaults`.
+    pub fn or(&self, defaults: &LabelStyle) -> LabelStyle {
+        LabelStyle {
+            font: self.font.clone().or_else(|| defaults.font.clone()),
+            size: self.size.or(defaults.size),
+            color: self.color.or(defaults.color),
+            outline: self.outline.or(defaults.outline),
+            shadow: self.shadow.or(defaults.shadow),
+            background: self.background.or(defaults.background),
+            position: self.position.or(defaults.position),
+            hide_after: self.hide_after.or(defaults.hide_after),
+            only_on_fallback: self.only_on_fallback.or(defaults.only_on_fallback),
+            hidden: self.hidden.or(defaults.hidden),
+        }
+    }
+
+    pub fn only_on_fallback(&self) -> bool {
+        self.only_on_fallback.unwrap_or_default()
+    }
+
+    pub fn size(&self) -> u32 {
+        self.size.unwrap_or(20)
+    }
+
+    /// The textoverlay properties for this style, for use in a pipeline description.
+    pub fn properties(&self) -> String {
+        let font = self.font.as_deref().unwrap_or("Arial");
+        let size = self.size();
+        let mut properties = format!(r#"font-desc="{font} {size}""#);
+        if let Some(color) = self.color {
+            properties += &format!(" color={}", color.0);
+        }
+        if let Some(outline) = self.outline {
+            properties += &format!(" draw-outline={outline}");
+        }
+        if let Some(shadow) = self.shadow {
+            properties += &format!(" draw-shadow={shadow}");
+        }
+        if let Some(background) = self.background {
+            properties += &format!(" shaded-background={background}");
+        }
+        if let Some(position) = self.position {
+            let (halignment, valignment) = position.alignment();
+            properties += &format!(" halignment={halignment} valignment={valignment}");
+        }
+        if self.hidden.unwrap_or_default() || self.only_on_fallback() {
+            properties += " silent=true";
+        }
+        properties
+    }
+}
+
+/// Show and hide a label as its source's fallbackswitch changes inputs, and
+/// after `hide_after` seconds.
+pub fn watch_label(
+    style: &LabelStyle,
+    label: &gstreamer::Element,
+    fallbackswitch: &gstreamer::Element,
+    primary: &gstreamer::Pad,
+) {
+    if style.hidden.unwrap_or_default() {
+        return;
+    }
+    let only_on_fallback = style.only_on_fallback();
+    let hide_after = style.hide_after;
+    if !only_on_fallback && hide_after.is_none() {
+        return;
+    }
+
+    // Each time the label is shown, older hide timers are ignored
+    let generation = Arc::new(AtomicU64::new(0));
+    let show = {
+        let label = label.downgrade();
+        move || {
+            let Some(label) = label.upgrade() else {
+                return;
+            };
+            label.set_property("silent", false);
+            let Some(hide_after) = hide_after else {
+                return;
+            };
+            let shown = generation.fetch_add(1, Ordering::Relaxed) + 1;
+            let generation = generation.clone();
+            let label = label.downgrade();
+            glib::timeout_add_seconds_once(hide_after as _, move || {
+                if generation.load(Ordering::Relaxed) == shown
+                    && let Some(label) = label.upgrade()
+                {
+                    label.set_property("silent", true);
+                }
+            });
+        }
+    };
+
+    if !only_on_fallback {
+        show();
+    }
+
+    let label = label.downgrade();
+    let primary = primary.downgrade();
+    fallbackswitch.connect_notify(Some("active-pad"), move |fallbackswitch, _| {
+        let active = fallbackswitch.property::<Option<gstreamer::Pad>>("active-pad");
+        let on_primary = active.is_some() && active == primary.upgrade();
+        if !on_primary {
+            show();
+        } else if only_on_fallback && let Some(label) = label.upgrade() {
+            label.set_property("silent", true);
+        }
+    });
+}
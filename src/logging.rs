@@ -0,0 +1,51 @@
+//! Log output, filtered by level, as text or JSON.
+
+use serde::Deserialize;
+use tracing_subscriber::EnvFilter;
+
+/// Overrides `[logging] level`, eg: `PI_FRAME_LOG=debug`
+const LOG_ENV: &str = "PI_FRAME_LOG";
+
+/// `[logging]`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    /// A level, optionally followed by levels for individual modules, eg:
+    /// `info,pi_frame::motion=debug`
+    level: String,
+    format: LogFormat,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        Self {
+            level: "info".to_string(),
+            format: LogFormat::Text,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum LogFormat {
+    Text,
+    /// One JSON object per line, for log shippers
+    Json,
+}
+
+/// Start logging to stderr.
+pub fn init(config: &LoggingConfig) -> Result<(), Box<dyn std::error::Error>> {
+    let filter = match std::env::var(LOG_ENV) {
+        Ok(level) => EnvFilter::try_new(&level).map_err(|e| format!("{LOG_ENV}: {e}"))?,
+        Err(_) => EnvFilter::try_new(&config.level).map_err(|e| format!("logging.level: {e}"))?,
+    };
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr);
+    let result = match config.format {
+        LogFormat::Text => builder.try_init(),
+        LogFormat::Json => builder.json().with_current_span(true).try_init(),
+    };
+    result.map_err(|e| format!("can't start logging: {e}"))?;
+    Ok(())
+}
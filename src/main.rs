@@ -1,19 +1,55 @@
-use std::{collections::HashMap, path::Path, sync::atomic::AtomicBool, time::Duration};
+mod api;
+mod audio;
+mod cells;
+mod clock;
+mod control;
+mod fbimage;
+mod graphs;
+mod intercom;
+mod labels;
+mod logging;
+mod metrics;
+mod motion;
+mod mqtt;
+mod recordings;
+mod restream;
+mod schedule;
+mod snapshots;
+mod splash;
+mod supervisor;
+mod widgets;
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::Path,
+    rc::Rc,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{Duration, Instant},
+};
 
 use glib::property::PropertyGet;
 use gstreamer::{Bin, GhostPad, MessageView, prelude::*};
 use gstreamer_video::VideoInfo;
 use serde::Deserialize;
+use tracing::{debug, error, info, trace, warn};
 
 const RTSP_PREFIX: &str = "rtsp_";
+/// Frames per second drawn to the display
+const FRAMERATE: u32 = 24;
 
 fn stream_rtsp(
     url: &str,
-    id: &str,
+    source: &InstantiatedSource,
     width: usize,
     height: usize,
     scale: RtspScale,
 ) -> Result<gstreamer::Element, Box<dyn std::error::Error>> {
+    let id = &source.name;
+    let motion = source.source.motion.as_ref();
+    let recording = source.recordings.as_ref();
     let bin = Bin::with_name(id);
 
     let (scale, scale_opts) = match scale {
@@ -30,6 +66,16 @@ fn stream_rtsp(
     let watchdog_id = format!("{id}_watchdog");
     let decoder_id = format!("{id}_decoder");
     let videoconvertscale_id = format!("{id}_videoconvertscale");
+    let motion_id = format!("{id}_motion");
+    let snapshot_branch = snapshots::branch_description(&format!("{id}_snapshot_tee"), &id);
+    let record_branch = recording
+        .map(|recording| {
+            recordings::branch_description(&format!("{id}_record_tee"), &id, recording.pre_roll)
+        })
+        .unwrap_or_default();
+    let motion_branch = motion
+        .map(|_| motion::Motion::branch_description(&format!("{id}_tee"), &motion_id))
+        .unwrap_or_default();
     
     let rtspsrc = gstreamer::parse::launch( &format!(r#"
     rtspsrc location={url:?} name={id:?} buffer-mode=none latency=2000 drop-on-latency=true protocols=tcp"#))?;
@@ -39,9 +85,12 @@ fn stream_rtsp(
         r#"
                 queue name=netqueue max-size-time=2000000000 leaky=downstream
                 ! rtph264depay wait-for-keyframe=true ! h264parse config-interval=1
+                {record_branch}
                 ! queue name=parsequeue max-size-time=2000000000 leaky=downstream
                 ! v4l2h264dec name={decoder_id:?}
                 ! watchdog name={watchdog_id:?} timeout=30000
+                {snapshot_branch}
+                {motion_branch}
                 {scale} 
                 ! queue leaky=downstream max-size-time=2000000000
                 ! videoconvertscale name={videoconvertscale_id:?}  {scale_opts}
@@ -50,25 +99,42 @@ fn stream_rtsp(
     "#
     ), true, "sink")?;
 
+    if let Some(intercom) = &source.intercom {
+        intercom.watch(&source.name, &rtspsrc);
+    }
+
     bin.add(&rtspsrc)?;
     bin.add(&pipeline)?;
 
     let pipeline_pad = pipeline.static_pad("sink").expect("no sink");
 
     let bin_clone = bin.clone();
+    let span = tracing::Span::current();
+    let audio = source.audio.clone();
+    let name = source.name.clone();
 
     rtspsrc.connect_pad_added(move |src, src_pad| {
+        let _span = span.enter();
         let caps = src_pad.current_caps().expect("no caps");
-        let accept = caps.structure(0)
+        let media = caps.structure(0)
             .and_then(|s| s.get::<&str>("media").ok())
-            .map(|media| media == "video")
-            .unwrap_or(false);
+            .unwrap_or_default();
+        let accept = media == "video";
+        // Only the first audio stream is played
+        let audio = audio
+            .as_ref()
+            .filter(|_| media == "audio" && bin_clone.static_pad("audio").is_none());
 
         if accept {
-            eprintln!("Accepting video stream for {}", id);
+            debug!("Accepting video stream for {id}");
             src_pad.link(&pipeline_pad).expect("no link");
+        } else if let Some(audio) = audio {
+            debug!("Accepting audio stream for {id}");
+            if let Err(e) = play_audio(&bin_clone, src_pad, audio, &name) {
+                error!("Failed to play audio: {e}");
+            }
         } else {
-            eprintln!("Rejecting stream for {}", id);
+            debug!("Rejecting stream for {id}");
 
             let fs = gstreamer::ElementFactory::make("fakesink").build().expect("fakesink");
             bin_clone.add(&fs).unwrap();
@@ -82,6 +148,10 @@ fn stream_rtsp(
     let decoder_src = decoder.static_pad("src").expect("no src");
     probe_image_format(&decoder_id, &decoder_src);
 
+    if let Some(motion) = motion {
+        motion.watch(&pipeline.by_name(&motion_id).expect("no motion detector"));
+    }
+
     let sink = bin.by_name("sink").expect("no sink");
     let sink_pad = sink.static_pad("src").expect("static pad");
 
@@ -91,6 +161,28 @@ fn stream_rtsp(
     Ok(bin.upcast())
 }
 
+/// Decode an RTSP source's audio stream, and connect it to the mixer's output
+/// through the source bin's `audio` pad.
+fn play_audio(
+    bin: &Bin,
+    src_pad: &gstreamer::Pad,
+    mixer: &audio::Mixer,
+    source: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let audio = gstreamer::parse::bin_from_description(mixer.branch_description(), true)?;
+    bin.add(&audio)?;
+    let ghost = GhostPad::builder_with_target(&audio.static_pad("src").ok_or("no src")?)?
+        .name("audio")
+        .build();
+    ghost.set_active(true)?;
+    bin.add_pad(&ghost)?;
+    // Connected all the way before any audio arrives, or it'd be not-linked
+    mixer.add(source, ghost.upcast_ref())?;
+    audio.sync_state_with_parent()?;
+    src_pad.link(&audio.static_pad("sink").ok_or("no sink")?)?;
+    Ok(())
+}
+
 fn stream_image(
     image: &str,
     width: usize,
@@ -125,10 +217,12 @@ fn stream_image(
 
     let image = bin.by_name("image").expect("no image");
     let image_pad = image.static_pad("src").expect("no src");
+    let span = tracing::Span::current();
     image_pad.add_probe(gstreamer::PadProbeType::BUFFER, move |pad, _buffer| {
+        let _span = span.enter();
         if let Some(caps) = pad.current_caps() {
             if let Ok(vinfo) = VideoInfo::from_caps(&caps) {
-                println!(
+                debug!(
                     "Image bounds: {}x{}, format: {}",
                     vinfo.width(),
                     vinfo.height(),
@@ -173,7 +267,7 @@ fn stream_videotestsrc(
     Ok(bin.upcast())
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct CompositorPad {
     pad: gstreamer::Pad,
     x: i32,
@@ -186,27 +280,25 @@ fn make_compositor(
     width: usize,
     height: usize,
     layout: Layout,
-    time: Option<String>,
+    time: Option<&clock::Clock>,
+    restream: Option<&restream::RestreamConfig>,
 ) -> Result<(gstreamer::Element, Vec<CompositorPad>), Box<dyn std::error::Error>> {
     let time = time
-        .map(|time| {
-            format!(
-                r#"! clockoverlay halignment=right valignment=bottom
-                    time-format={time:?} font-desc="Arial 8"
-                    halignment=absolute valignment=absolute
-                    x-absolute=1 y-absolute=1
-                    "#,
-            )
-        })
+        .map(|time| format!("! {}", time.description()))
         .unwrap_or_default();
+    let restream = match restream {
+        Some(restream) => restream.branch_description("restream_tee", FRAMERATE)?,
+        None => String::new(),
+    };
     let pipeline = gstreamer::parse::launch(&format!(
         r#"
     compositor name="mixer" background=black
         ! videorate drop-only=true
         ! videoconvert
-        ! video/x-raw,framerate=24/1,width={width},height={height},pixel-aspect-ratio=1/1,format=RGB16
+        ! video/x-raw,framerate={FRAMERATE}/1,width={width},height={height},pixel-aspect-ratio=1/1,format=RGB16
         {time}
-        ! fbdevsink sync=false
+        {restream}
+        ! fbdevsink name=fbsink sync=false
     "#
     ))?;
     let pipeline = pipeline.downcast::<gstreamer::Bin>().expect("not a bin");
@@ -241,10 +333,82 @@ fn make_compositor(
 #[derive(Debug, Deserialize, Clone)]
 struct Source {
     description: String,
+    #[serde(default)]
+    label: labels::LabelStyle,
+    #[serde(default)]
+    fallback: Fallback,
+    /// The old way of setting `fallback = { image = ... }`, still accepted
+    placeholder: Option<String>,
+    #[serde(default)]
+    failover: Failover,
+    motion: Option<motion::Motion>,
+    /// Play the source's audio, when it's the one chosen to be heard
+    #[serde(default)]
+    audio: bool,
+    /// Offer the camera a backchannel, so it can be talked to
+    #[serde(default)]
+    intercom: bool,
     #[serde(flatten)]
     source: SourceType,
 }
 
+#[derive(Debug, Deserialize, Clone)]
+struct Fallback {
+    /// Seconds without video before switching to the fallback
+    #[serde(default = "default_fallback_timeout")]
+    timeout: u64,
+    /// What to show instead, defaulting to the display's placeholder
+    #[serde(flatten)]
+    content: Option<FallbackContent>,
+}
+
+fn default_fallback_timeout() -> u64 {
+    10
+}
+
+impl Default for Fallback {
+    fn default() -> Self {
+        Self {
+            timeout: default_fallback_timeout(),
+            content: None,
+        }
+    }
+}
+
+/// When to move between an RTSP source's URLs
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+struct Failover {
+    /// Switch to the next URL after this many restarts...
+    failures: usize,
+    /// ...within this many seconds
+    window: u64,
+    /// Seconds to wait before trying the primary URL again
+    retry_primary: u64,
+}
+
+impl Default for Failover {
+    fn default() -> Self {
+        Self {
+            failures: 3,
+            window: 60,
+            retry_primary: 300,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(untagged)]
+enum FallbackContent {
+    /// Keep the last frame on screen, dimmed
+    Freeze { freeze: bool },
+    /// An image, or numbered images played in a loop
+    Image { image: String },
+    Videotestsrc { videotestsrc: String },
+    /// A secondary stream, eg: a camera's low-res substream
+    Rtsp { rtsp: String },
+}
+
 #[derive(Copy, Clone, Debug, Deserialize)]
 #[serde(rename_all = "lowercase")]
 enum RtspScale {
@@ -260,7 +424,9 @@ enum RtspScale {
 #[serde(untagged)]
 enum SourceType {
     Rtsp {
-        rtsp: String,
+        /// One or more streams, in order of preference
+        #[serde(deserialize_with = "one_or_many")]
+        rtsp: Vec<RtspStream>,
         scale: RtspScale,
     },
     Videotestsrc {
@@ -273,17 +439,149 @@ enum SourceType {
     },
 }
 
+/// One of a camera's RTSP streams
+#[derive(Debug, Deserialize, Clone)]
+#[serde(from = "RtspStreamConfig")]
+struct RtspStream {
+    url: String,
+    /// The stream's resolution, if known
+    size: Option<(usize, usize)>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RtspStreamConfig {
+    Url(String),
+    Table {
+        url: String,
+        width: Option<usize>,
+        height: Option<usize>,
+    },
+}
+
+impl From<RtspStreamConfig> for RtspStream {
+    fn from(config: RtspStreamConfig) -> Self {
+        match config {
+            RtspStreamConfig::Url(url) => Self { url, size: None },
+            RtspStreamConfig::Table { url, width, height } => Self {
+                url,
+                size: width.zip(height),
+            },
+        }
+    }
+}
+
+fn one_or_many<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<RtspStream>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(RtspStream),
+        Many(Vec<RtspStream>),
+    }
+
+    let streams = match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(stream) => vec![stream],
+        OneOrMany::Many(streams) => streams,
+    };
+    if streams.is_empty() {
+        return Err(serde::de::Error::custom("at least one URL is required"));
+    }
+    Ok(streams)
+}
+
+/// Pick the smallest stream that is at least `width`x`height`, or the largest
+/// if none are big enough. Streams without a known size are only used if none
+/// have one, in which case the first is preferred.
+fn select_stream(streams: &[RtspStream], width: usize, height: usize) -> usize {
+    let sized = streams
+        .iter()
+        .enumerate()
+        .filter_map(|(index, stream)| stream.size.map(|size| (index, size)));
+    let area = |(_, (width, height)): &(usize, (usize, usize))| width * height;
+    let big_enough = sized
+        .clone()
+        .filter(|(_, (w, h))| *w >= width && *h >= height)
+        .min_by_key(area);
+    big_enough
+        .or_else(|| sized.max_by_key(area))
+        .map(|(index, _)| index)
+        .unwrap_or_default()
+}
+
+/// The stream to restart a source on when it goes from producing `from` sized
+/// video to `to` sized, or `None` if it can carry on as it is. It still needs
+/// restarting on the same stream if the size changes, to rescale it.
+fn stream_for_resize(
+    streams: &[RtspStream],
+    primary: usize,
+    from: (usize, usize),
+    to: (usize, usize),
+) -> Option<usize> {
+    let selected = select_stream(streams, to.0, to.1);
+    (selected != primary || from != to).then_some(selected)
+}
+
 #[derive(Debug, Deserialize, Clone)]
 struct Config {
     display: Display,
     sources: Vec<Source>,
+    #[serde(default)]
+    supervisor: SupervisorConfig,
+    #[serde(default)]
+    widgets: Vec<widgets::Widget>,
+    #[serde(default)]
+    schedule: schedule::ScheduleConfig,
+    mqtt: Option<mqtt::MqttConfig>,
+    api: Option<api::ApiConfig>,
+    #[serde(default)]
+    logging: logging::LoggingConfig,
+    #[serde(default)]
+    graphs: graphs::GraphConfig,
+    snapshots: Option<snapshots::SnapshotConfig>,
+    recordings: Option<recordings::RecordingConfig>,
+    restream: Option<restream::RestreamConfig>,
+    #[serde(default)]
+    audio: audio::AudioConfig,
+    #[serde(default)]
+    intercom: intercom::IntercomConfig,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+struct SupervisorConfig {
+    /// Rebuild the pipeline if no frame reaches the display for this many seconds
+    stall_timeout: u64,
+    /// Seconds to wait before retrying a pipeline rebuild that failed
+    retry_delay: u64,
+}
+
+impl Default for SupervisorConfig {
+    fn default() -> Self {
+        Self {
+            stall_timeout: 10,
+            retry_delay: 5,
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Clone)]
 struct Display {
     framebuffer: String,
     layout: Layout,
-    time: Option<String>,
+    time: Option<clock::Clock>,
+    /// Image drawn to the framebuffer at startup, until the sources appear
+    startup_image: Option<String>,
+    /// Image drawn to the framebuffer when pi-frame exits
+    shutdown_image: Option<String>,
+    /// Image or video shown in a cell while its source is connecting
+    placeholder: Option<String>,
+    #[serde(default)]
+    labels: labels::LabelStyle,
+    /// Other layouts that can be switched to by name
+    #[serde(default)]
+    layouts: HashMap<String, Layout>,
 }
 
 #[derive(Debug, Deserialize, Clone, Copy)]
@@ -292,6 +590,20 @@ struct Layout {
     vertical: usize,
 }
 
+impl Layout {
+    /// Make sure there's a cell for every source.
+    fn check(&self, sources: usize) -> Result<(), String> {
+        let cells = self.horizontal * self.vertical;
+        if cells < sources {
+            return Err(format!(
+                "a {}x{} layout only has room for {cells} of the {sources} sources",
+                self.horizontal, self.vertical
+            ));
+        }
+        Ok(())
+    }
+}
+
 #[derive(Debug, Clone)]
 /// A source that has been instantiated and added to the pipeline
 struct InstantiatedSource {
@@ -300,13 +612,165 @@ struct InstantiatedSource {
     index: usize,
     width: usize,
     height: usize,
+    /// The size of the whole display, for when the source is focused
+    screen: (usize, usize),
+    /// Shared between clones, so it survives the source being restarted
+    selection: Arc<Mutex<StreamSelection>>,
+    state: Arc<Mutex<SourceState>>,
+    /// Whether the source is currently producing full-screen video
+    fullscreen: Arc<AtomicBool>,
+    /// Frames reaching the source's cell
+    frames: metrics::FrameCounter,
+    recordings: Option<recordings::RecordingConfig>,
+    /// Set if the source's audio is played
+    audio: Option<audio::Mixer>,
+    /// Set if the source's camera is offered a backchannel
+    intercom: Option<intercom::Intercom>,
+}
+
+impl InstantiatedSource {
+    /// The size of video the source should produce.
+    fn output_size(&self) -> (usize, usize) {
+        if self.fullscreen.load(Ordering::Relaxed) {
+            self.screen
+        } else {
+            (self.width, self.height)
+        }
+    }
+
+    /// Context for log messages about the source.
+    fn span(&self) -> tracing::Span {
+        tracing::info_span!("source", id = %self.name, description = %self.source.description)
+    }
+}
+
+/// What's currently happening with a source
+#[derive(Debug)]
+struct SourceState {
+    /// Whether the cell is showing its fallback instead of the source's video
+    on_fallback: bool,
+    /// How many times the source has been restarted, by reason
+    restarts: BTreeMap<&'static str, u32>,
+}
+
+impl Default for SourceState {
+    fn default() -> Self {
+        // Sources start out connecting, with their fallback showing
+        Self {
+            on_fallback: true,
+            restarts: BTreeMap::new(),
+        }
+    }
+}
+
+/// Which of an RTSP source's URLs is in use, and its recent failures
+#[derive(Debug, Default)]
+struct StreamSelection {
+    /// The stream best suited to the cell, which we return to after failing over
+    primary: usize,
+    index: usize,
+    failures: Vec<Instant>,
+    switched_at: Option<Instant>,
+    /// Set while trying the primary URL again, along with the URL to go back to
+    /// if it fails
+    probing: Option<(Instant, usize)>,
+}
+
+impl StreamSelection {
+    fn new(primary: usize) -> Self {
+        Self {
+            primary,
+            index: primary,
+            ..Default::default()
+        }
+    }
+
+    /// Record a restart, moving to the next URL if the current one keeps failing.
+    fn record_failure(&mut self, failover: &Failover, urls: &[RtspStream]) {
+        let now = Instant::now();
+        if let Some((_, previous)) = self.probing.take() {
+            warn!("URL #{} is still failing, going back to URL #{previous}", self.primary);
+            self.switch_to(previous, now);
+            return;
+        }
+
+        let window = Duration::from_secs(failover.window);
+        self.failures.retain(|failure| now - *failure < window);
+        self.failures.push(now);
+        if urls.len() > 1 && self.failures.len() >= failover.failures {
+            let next = (self.index + 1) % urls.len();
+            warn!(
+                "URL #{} failed {} times in {}s, switching to URL #{next}",
+                self.index,
+                self.failures.len(),
+                failover.window
+            );
+            self.switch_to(next, now);
+        }
+    }
+
+    /// Whether it's time to try the primary URL again. Also notices when a previous
+    /// attempt has survived long enough to count as a success.
+    fn should_retry_primary(&mut self, failover: &Failover) -> bool {
+        if let Some((since, _)) = self.probing {
+            if since.elapsed() >= Duration::from_secs(failover.window) {
+                self.probing = None;
+            }
+            return false;
+        }
+        self.index != self.primary
+            && self
+                .switched_at
+                .is_some_and(|at| at.elapsed() >= Duration::from_secs(failover.retry_primary))
+    }
+
+    fn retry_primary(&mut self) {
+        let now = Instant::now();
+        self.probing = Some((now, self.index));
+        self.switch_to(self.primary, now);
+    }
+
+    /// Make another stream the primary, eg: when the cell changes size.
+    fn set_primary(&mut self, primary: usize) {
+        self.primary = primary;
+        self.probing = None;
+        self.switch_to(primary, Instant::now());
+    }
+
+    fn switch_to(&mut self, index: usize, now: Instant) {
+        self.index = index;
+        self.failures.clear();
+        self.switched_at = Some(now);
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 enum RestartReason {
     Timeout,
     Error,
-    Reentrant,
+    /// Trying a source's primary URL again after failing over
+    RetryPrimary,
+    /// Switching to the stream that best suits the cell's new size
+    Resize,
+    /// Asked for through the API
+    Manual,
+}
+
+impl RestartReason {
+    fn as_str(&self) -> &'static str {
+        match self {
+            RestartReason::Timeout => "timeout",
+            RestartReason::Error => "error",
+            RestartReason::RetryPrimary => "retry-primary",
+            RestartReason::Resize => "resize",
+            RestartReason::Manual => "manual",
+        }
+    }
+
+    /// Whether the source stopped working, rather than being restarted on purpose
+    fn is_failure(&self) -> bool {
+        matches!(self, RestartReason::Timeout | RestartReason::Error)
+    }
 }
 
 fn restart_source(
@@ -314,24 +778,61 @@ fn restart_source(
     source: &InstantiatedSource,
     reason: RestartReason,
 ) {
-    println!("Restarting source: {}", source.name);
+    let _span = source.span().entered();
+    warn!(reason = reason.as_str(), "Restarting source");
 
-    static RESTART_LOCK: std::sync::LazyLock<std::sync::Mutex<HashMap<String, bool>>> = std::sync::LazyLock::new(|| std::sync::Mutex::new(HashMap::new()));
+    // Sources being restarted, and what to restart them for again afterwards
+    static RESTART_LOCK: std::sync::LazyLock<
+        std::sync::Mutex<HashMap<String, Option<RestartReason>>>,
+    > = std::sync::LazyLock::new(|| std::sync::Mutex::new(HashMap::new()));
     let mut restart_lock = RESTART_LOCK.lock().unwrap();
-    if restart_lock.get(&source.name).is_some() {
-        // Ensure no re-entrancy
-        if reason != RestartReason::Reentrant {
-            let pipeline = pipeline.clone();
-            let source = source.clone();
-            glib::idle_add(move || {
-                restart_source(&pipeline, &source, RestartReason::Reentrant);
-                glib::ControlFlow::Break
-            });
+    if let Some(pending) = restart_lock.get_mut(&source.name) {
+        // Ensure no re-entrancy. One outage can both post an error and trip the
+        // watchdog, and the restart that's already underway deals with it, so
+        // it's only counted once. Anything else is done once it's finished.
+        if !reason.is_failure() {
+            *pending = Some(reason);
         }
         return;
     }
-    restart_lock.insert(source.name.clone(), true);
+    restart_lock.insert(source.name.clone(), None);
     drop(restart_lock);
+    *source
+        .state
+        .lock()
+        .unwrap()
+        .restarts
+        .entry(reason.as_str())
+        .or_default() += 1;
+
+    if let SourceType::Rtsp { rtsp, .. } = &source.source.source {
+        let mut selection = source.selection.lock().unwrap();
+        match reason {
+            RestartReason::RetryPrimary => selection.retry_primary(),
+            RestartReason::Timeout | RestartReason::Error => {
+                selection.record_failure(&source.source.failover, rtsp)
+            }
+            // The selection has already been changed
+            RestartReason::Resize => {}
+            // Not the stream's fault
+            RestartReason::Manual => {}
+        }
+    }
+
+    let status = match reason {
+        RestartReason::Timeout => "Reconnecting (timed out)...",
+        RestartReason::Error => "Reconnecting (error)...",
+        RestartReason::RetryPrimary => "Reconnecting...",
+        RestartReason::Resize => "Switching streams...",
+        RestartReason::Manual => "Restarting...",
+    };
+    set_source_status(pipeline, source, status);
+
+    let structure = gstreamer::Structure::builder("restart")
+        .field("source", &source.name)
+        .field("reason", reason.as_str())
+        .build();
+    let _ = pipeline.post_message(gstreamer::message::Application::new(structure));
 
     let bin = pipeline.by_name(&source.name).expect("no bin").downcast::<gstreamer::Bin>().expect("not a bin");
 
@@ -340,15 +841,18 @@ fn restart_source(
     let pipeline = pipeline.clone();
     let source = source.clone();
     glib::idle_add(move || {
+        let _span = source.span().entered();
         if let Err(e) = restart_inner_deferred(&pipeline, &bin, &source) {
-            eprintln!("*** Failed to restart source {}: {e:?}", source.name);
+            error!("Failed to restart source: {e:?}");
         }
 
-        let mut restart_lock = RESTART_LOCK.lock().unwrap();
-        restart_lock.remove(&source.name);
+        let pending = RESTART_LOCK.lock().unwrap().remove(&source.name).flatten();
+
+        info!("Restarted source");
+        if let Some(reason) = pending {
+            restart_source(&pipeline, &source, reason);
+        }
 
-        println!("Restarted source: {}", source.name);
-        
         glib::ControlFlow::Break
     });
 }
@@ -358,15 +862,13 @@ fn restart_inner_deferred(pipeline: &gstreamer::Pipeline, bin: &gstreamer::Bin,
     let pad = bin.static_pad("src").expect("no src");
     let peer = pad.peer().expect("no peer");
     pad.unlink(&peer)?;
-    let peer_parent = peer
-        .parent()
-        .expect("no parent")
-        .downcast::<gstreamer::Element>()
-        .expect("not an element");
+    if let Some(mixer) = &source.audio {
+        mixer.remove(&source.name);
+    }
 
     match bin.set_state(gstreamer::State::Null) {
-        Ok(_) => eprintln!("Set bin to null"),
-        Err(e) => eprintln!("Error setting bin to null: {e:?}"),
+        Ok(_) => debug!("Set bin to null"),
+        Err(e) => warn!("Error setting bin to null: {e:?}"),
     }
     pipeline.remove(bin)?;
 
@@ -374,7 +876,7 @@ fn restart_inner_deferred(pipeline: &gstreamer::Pipeline, bin: &gstreamer::Bin,
     pipeline.add(&element)?;
 
     // Link the new element to the peer pad
-    element.link(&peer_parent)?;
+    element.static_pad("src").expect("no src").link(&peer)?;
     element.sync_state_with_parent()?;
 
     Ok(())
@@ -383,14 +885,17 @@ fn restart_inner_deferred(pipeline: &gstreamer::Pipeline, bin: &gstreamer::Bin,
 fn create_source(
     source: &InstantiatedSource,
 ) -> Result<gstreamer::Element, Box<dyn std::error::Error>> {
+    let _span = source.span().entered();
     let stream = match &source.source.source {
         SourceType::Rtsp { rtsp, scale } => {
-            eprintln!("Configuring RTSP source: {rtsp}");
-            let stream = stream_rtsp(&rtsp, &source.name, source.width, source.height, *scale)?;
+            let rtsp = &rtsp[source.selection.lock().unwrap().index].url;
+            info!("Configuring RTSP source: {rtsp}");
+            let (width, height) = source.output_size();
+            let stream = stream_rtsp(rtsp, source, width, height, *scale)?;
             stream
         }
         SourceType::Videotestsrc { videotestsrc } => {
-            eprintln!("Configuring videotestsrc source: {videotestsrc}");
+            info!("Configuring videotestsrc source: {videotestsrc}");
             let stream = stream_videotestsrc(&videotestsrc, source.width, source.height)?;
             stream
         }
@@ -399,7 +904,7 @@ fn create_source(
             width: scale_width,
             height: scale_height,
         } => {
-            eprintln!("Configuring image source: {image:?}");
+            info!("Configuring image source: {image:?}");
             let scale = match (*scale_width, *scale_height) {
                 (Some(width), Some(height)) => Some((width, height)),
                 (None, None) => None,
@@ -414,10 +919,12 @@ fn create_source(
 
 fn probe_image_format(name: &str, pad: &gstreamer::Pad) {
     let name = name.to_string();
+    let span = tracing::Span::current();
     pad.add_probe(gstreamer::PadProbeType::BUFFER, move |pad, _buffer| {
+        let _span = span.enter();
         if let Some(caps) = pad.current_caps() {
             if let Ok(vinfo) = VideoInfo::from_caps(&caps) {
-                println!(
+                debug!(
                     "Image bounds for {}: {}x{}, format: {}",
                     name,
                     vinfo.width(),
@@ -430,271 +937,1071 @@ fn probe_image_format(name: &str, pad: &gstreamer::Pad) {
     });
 }
 
-struct SnapshotRequester {
-    tx: std::sync::mpsc::Sender<Box<dyn FnOnce(image::ImageResult<Vec<u8>>) + Send>>,
-    handle: std::thread::JoinHandle<()>,
+/// The pipeline description for the fallback input of a cell, which is shown
+/// while its source isn't producing video. Placeholders have the source's
+/// connection state drawn on top.
+///
+/// Secondary RTSP streams are created separately and linked to the
+/// `secondary` element, and frozen frames don't need a fallback input at all.
+fn fallback_description(
+    source: &InstantiatedSource,
+    content: Option<&FallbackContent>,
+) -> Option<String> {
+    let (width, height) = (source.width, source.height);
+    let background = match content {
+        Some(FallbackContent::Freeze { freeze: true }) => return None,
+        Some(FallbackContent::Rtsp { .. }) => {
+            return Some("identity name=secondary silent=true".to_string());
+        }
+        Some(FallbackContent::Image { image }) if !is_image_sequence(image) => format!(
+            r#"filesrc location={image:?} ! decodebin ! imagefreeze
+                ! videorate ! video/x-raw,framerate=1/1
+                ! videoscale add-borders=true ! videoconvert
+                ! video/x-raw,width={width},height={height},pixel-aspect-ratio=1/1"#
+        ),
+        Some(FallbackContent::Image { image }) => format!(
+            r#"multifilesrc location={image:?} loop=true
+                caps="{},framerate={SEQUENCE_FRAMERATE}/1" ! decodebin
+                ! videoscale add-borders=true ! videoconvert
+                ! video/x-raw,width={width},height={height},pixel-aspect-ratio=1/1"#,
+            image_caps(image).unwrap_or("image/png")
+        ),
+        Some(FallbackContent::Videotestsrc { videotestsrc }) => format!(
+            "videotestsrc pattern={videotestsrc} ! video/x-raw,width={width},height={height}"
+        ),
+        Some(FallbackContent::Freeze { freeze: false }) | None => {
+            "videotestsrc pattern=black ! alpha alpha=0.5".to_string()
+        }
+    };
+    Some(format!(
+        r#"{background}
+            ! textoverlay name={:?} text="{CONNECTING}" font-desc="Arial 16"
+                valignment=center halignment=center
+            ! queue"#,
+        status_overlay_name(source)
+    ))
 }
 
-impl SnapshotRequester {
-    fn request(&self, f: impl FnOnce(image::ImageResult<Vec<u8>>) + Send + 'static) {
-        self.tx.send(Box::new(f)).unwrap();
+/// The source for a secondary stream, restarted independently of its primary.
+fn secondary_source(source: &InstantiatedSource, rtsp: &str) -> InstantiatedSource {
+    let scale = match source.source.source {
+        SourceType::Rtsp { scale, .. } => scale,
+        _ => RtspScale::Fit,
+    };
+    InstantiatedSource {
+        source: Source {
+            description: source.source.description.clone(),
+            label: source.source.label.clone(),
+            fallback: Fallback::default(),
+            placeholder: None,
+            failover: source.source.failover.clone(),
+            motion: None,
+            audio: false,
+            intercom: false,
+            source: SourceType::Rtsp {
+                rtsp: vec![RtspStream {
+                    url: rtsp.to_string(),
+                    size: None,
+                }],
+                scale,
+            },
+        },
+        name: format!("{}_secondary", source.name),
+        selection: Default::default(),
+        state: Default::default(),
+        fullscreen: Default::default(),
+        frames: Default::default(),
+        recordings: None,
+        audio: None,
+        intercom: None,
+        ..source.clone()
     }
 }
 
-fn rgb565_to_rgb888(buf: &[u8]) -> Vec<u8> {
-    buf.chunks(2).flat_map(|b| {
-        let pixel = u16::from_le_bytes([b[0], b[1]]);
-        let r = ((pixel >> 11) & 0x1F) << 3;
-        let g = ((pixel >> 5) & 0x3F) << 2;
-        let b = (pixel & 0x1F) << 3;
-        [r as _, g as _, b as _]
-    }).collect()
+/// Once an RTSP source has failed over to another URL, periodically try to
+/// switch back to the primary.
+fn retry_primary_periodically(pipeline: &gstreamer::Pipeline, source: &InstantiatedSource) {
+    let pipeline = pipeline.downgrade();
+    let source = source.clone();
+    glib::timeout_add_seconds_local(10, move || {
+        let Some(pipeline) = pipeline.upgrade() else {
+            return glib::ControlFlow::Break;
+        };
+        let retry = source
+            .selection
+            .lock()
+            .unwrap()
+            .should_retry_primary(&source.source.failover);
+        if retry {
+            info!(parent: &source.span(), "Trying primary URL again");
+            restart_source(&pipeline, &source, RestartReason::RetryPrimary);
+        }
+        glib::ControlFlow::Continue
+    });
 }
 
-fn start_framebuffer_snapshot_thread(framebuffer: framebuffer::Framebuffer) -> SnapshotRequester {
-    use image::{write_buffer_with_format, ImageFormat, ExtendedColorType};
-    use std::io::Cursor;
-
-    let width = framebuffer.var_screen_info.xres;
-    let height = framebuffer.var_screen_info.yres;
-    let (tx, rx) = std::sync::mpsc::channel::<Box<dyn FnOnce(image::ImageResult<Vec<u8>>) + Send>>();
-
-    let handle = std::thread::spawn(move || {
-        while let Ok(f) = rx.recv() {
-            eprintln!("Taking snapshot");
-            let frame = framebuffer.read_frame();
-            let image = frame.to_vec();
-
-            let image = rgb565_to_rgb888(&image);
+/// With no fallback input, the compositor keeps showing the last frame it
+/// received. Dim it while the source is stalled.
+fn dim_when_frozen(output: &gstreamer::Pad, mixer_pad: &gstreamer::Pad, timeout: Duration) {
+    let last_buffer = Arc::new(Mutex::new(Instant::now()));
+    let dimmed = Arc::new(AtomicBool::new(false));
+
+    {
+        let last_buffer = last_buffer.clone();
+        let dimmed = dimmed.clone();
+        let mixer_pad = mixer_pad.clone();
+        output.add_probe(gstreamer::PadProbeType::BUFFER, move |_pad, _info| {
+            *last_buffer.lock().unwrap() = Instant::now();
+            if dimmed.swap(false, Ordering::Relaxed) {
+                mixer_pad.set_property("alpha", 1.0f64);
+            }
+            gstreamer::PadProbeReturn::Ok
+        });
+    }
 
-            let mut buf = Cursor::new(Vec::new());
-            let res = write_buffer_with_format(&mut buf, &image, width, height, ExtendedColorType::Rgb8, ImageFormat::Png);
-            f(res.map(|_| buf.into_inner()));
+    let mixer_pad = mixer_pad.downgrade();
+    glib::timeout_add_seconds_local(1, move || {
+        let Some(mixer_pad) = mixer_pad.upgrade() else {
+            return glib::ControlFlow::Break;
+        };
+        if last_buffer.lock().unwrap().elapsed() >= timeout
+            && !dimmed.swap(true, Ordering::Relaxed)
+        {
+            mixer_pad.set_property("alpha", 0.5f64);
         }
+        glib::ControlFlow::Continue
     });
-
-    SnapshotRequester { tx, handle }
 }
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let config_file = std::env::args()
-        .nth(1)
-        .expect("Config file must be the first argument");
-    let config_file = Path::new(&config_file).canonicalize()?;
-    let config_dir = config_file.parent().unwrap().to_owned();
-    let mut config = toml::from_str::<Config>(std::fs::read_to_string(config_file)?.as_str())?;
-
-    // Set up main loop
-    let main_loop = glib::MainLoop::new(None, false);
-
-    // Initialize GStreamer
-    gstreamer::init()?;
+/// The caps of an image file, going by its extension.
+fn image_caps(path: &str) -> Option<&'static str> {
+    let extension = Path::new(path)
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+    match extension.as_str() {
+        "png" => Some("image/png"),
+        "jpg" | "jpeg" => Some("image/jpeg"),
+        _ => None,
+    }
+}
 
-    gstfallbackswitch::plugin_register_static()?;
+/// Whether a path is a pattern for numbered images, eg: `frame%03d.png`, which
+/// are played in a loop.
+fn is_image_sequence(path: &str) -> bool {
+    path.contains('%')
+}
 
-    // Resolve image paths
-    for source in &mut config.sources {
-        match &mut source.source {
-            SourceType::Image { image, .. } => {
-                *image = config_dir
-                    .join(&image)
-                    .canonicalize()?
-                    .to_str()
-                    .expect("image path is not valid")
-                    .to_string();
-            }
-            _ => {}
-        }
+/// Make sure a placeholder can be shown. Only images can, as `multifilesrc`
+/// can loop numbered images, but not a video file.
+fn check_placeholder(path: &str) -> Result<(), String> {
+    match image_caps(path) {
+        Some(_) => Ok(()),
+        None => Err(format!(
+            "{path} can't be shown as a placeholder, only PNG or JPEG images and numbered image sequences, eg: frame%03d.png"
+        )),
     }
+}
 
-    let mut framebuffer = framebuffer::Framebuffer::new(&config.display.framebuffer)?;
-    let (width, height) = (
-        framebuffer.var_screen_info.xres,
-        framebuffer.var_screen_info.yres,
-    );
-    eprintln!("Framebuffer size: {width}x{height}");
-    eprintln!("Display var_screen_info: {:?}", framebuffer.var_screen_info);
+fn status_overlay_name(source: &InstantiatedSource) -> String {
+    format!("{}_status", source.name)
+}
 
-    // Clear the framebuffer in debug mode
-    if std::env::var("CLEAR_FRAMEBUFFER").is_ok() {
-        let frame = framebuffer.read_frame();
-        let zeros = vec![0; frame.len()];
-        framebuffer.write_frame(&zeros);
+/// What's drawn over a source's fallback until something goes wrong
+const CONNECTING: &str = "Connecting...";
+/// Frames per second numbered placeholder images are played at
+const SEQUENCE_FRAMERATE: u32 = 10;
+
+/// Update the connection state drawn over a source's fallback.
+fn set_source_status(pipeline: &gstreamer::Pipeline, source: &InstantiatedSource, status: &str) {
+    if let Some(overlay) = pipeline.by_name(&status_overlay_name(source)) {
+        overlay.set_property("text", status);
     }
+}
 
-    let snapshotter = start_framebuffer_snapshot_thread(framebuffer);
+/// A fully-constructed pipeline, along with the sources that were instantiated into it
+struct FramePipeline {
+    pipeline: gstreamer::Pipeline,
+    sources: HashMap<String, InstantiatedSource>,
+    /// The framebuffer sink's input pad, used to detect a stalled display
+    sink_pad: gstreamer::Pad,
+    cells: cells::Cells,
+    metrics: Arc<metrics::PipelineMetrics>,
+}
 
-    eprintln!("Config:");
-    eprintln!("{config:?}");
+fn build_pipeline(
+    config: &Config,
+    width: usize,
+    height: usize,
+    mixer: &audio::Mixer,
+    intercom: &intercom::Intercom,
+) -> Result<FramePipeline, Box<dyn std::error::Error>> {
+    config.display.layout.check(config.sources.len())?;
 
     let (compositor, pads) = make_compositor(
-        width as _,
-        height as _,
+        width,
+        height,
         config.display.layout,
-        config.display.time,
+        config.display.time.as_ref(),
+        config.restream.as_ref(),
     )?;
+    restream::feed(compositor.downcast_ref::<gstreamer::Bin>().expect("not a bin"));
+
+    let sink_pad = compositor
+        .downcast_ref::<gstreamer::Bin>()
+        .expect("not a bin")
+        .by_name("fbsink")
+        .expect("no fbsink")
+        .static_pad("sink")
+        .expect("no sink");
+
+    if let Some(time) = &config.display.time {
+        let overlay = compositor
+            .downcast_ref::<gstreamer::Bin>()
+            .expect("not a bin")
+            .by_name("clock")
+            .expect("no clock");
+        time.update(&overlay);
+    }
 
     let pipeline = gstreamer::Pipeline::with_name("pi-frame");
     pipeline.add(&compositor)?;
+    if config.sources.iter().any(|source| source.audio) {
+        mixer.build_output(&pipeline)?;
+    }
+    let cells = cells::Cells::new(&pipeline, width, height, mixer.clone());
 
     let mut sources = HashMap::new();
 
-    for (index, source) in config.sources.into_iter().enumerate() {
+    for (index, source) in config.sources.iter().enumerate() {
         let name = format!("src_{}", index);
+        let (cell_width, cell_height) = (
+            width / config.display.layout.horizontal,
+            height / config.display.layout.vertical,
+        );
+        let primary = match &source.source {
+            SourceType::Rtsp { rtsp, .. } => select_stream(rtsp, cell_width, cell_height),
+            _ => 0,
+        };
         let instantiated_source = InstantiatedSource {
             source: source.clone(),
             name: name.clone(),
             index,
-            width: (width as usize / config.display.layout.horizontal),
-            height: (height as usize / config.display.layout.vertical),
+            width: cell_width,
+            height: cell_height,
+            screen: (width, height),
+            selection: Arc::new(Mutex::new(StreamSelection::new(primary))),
+            state: Default::default(),
+            fullscreen: Default::default(),
+            frames: Default::default(),
+            recordings: config.recordings.clone(),
+            audio: source.audio.then(|| mixer.clone()),
+            intercom: source.intercom.then(|| intercom.clone()),
         };
 
         let element = create_source(&instantiated_source)?;
-        sources.insert(name, instantiated_source);
-
         pipeline.add(&element)?;
 
-        let fallback_timeout = Duration::from_secs(10).as_nanos();
+        let placeholder = config
+            .display
+            .placeholder
+            .clone()
+            .map(|image| FallbackContent::Image { image });
+        let content = source.fallback.content.as_ref().or(placeholder.as_ref());
+        let fallback = fallback_description(&instantiated_source, content)
+            .map(|fallback| format!("{fallback} ! fallback."))
+            .unwrap_or_default();
+
+        let fallback_timeout = Duration::from_secs(source.fallback.timeout);
+        let label_style = source.label.or(&config.display.labels);
         let text_overlay = gstreamer::parse::bin_from_description(&format!(
             r#"
-                fallbackswitch name=fallback immediate-fallback=true timeout={fallback_timeout}
-                    ! textoverlay text={:?} font-desc="Arial 20" scale-mode="none"
+                fallbackswitch name=fallback immediate-fallback=true timeout={}
+                    ! textoverlay name=label text={:?} {} scale-mode="none"
 
-                identity silent=true signal-handoffs=false ! fallback.
-                videotestsrc pattern=black ! alpha alpha=0.5 ! queue ! fallback.
+                identity name=primary silent=true signal-handoffs=false ! fallback.
+                {fallback}
                 "#,
-            source.description
+            fallback_timeout.as_nanos(),
+            source.description,
+            label_style.properties(),
         ), true)?;
         pipeline.add(&text_overlay)?;
-        element.link(&text_overlay)?;
+
+        {
+            let text_overlay = text_overlay.downcast_ref::<gstreamer::Bin>().expect("not a bin");
+            let primary = text_overlay
+                .by_name("primary")
+                .and_then(|primary| primary.static_pad("src"))
+                .and_then(|src| src.peer())
+                .expect("no primary pad");
+            instantiated_source.frames.watch(&primary);
+            let fallbackswitch = text_overlay.by_name("fallback").expect("no fallback");
+            labels::watch_label(
+                &label_style,
+                &text_overlay.by_name("label").expect("no label"),
+                &fallbackswitch,
+                &primary,
+            );
+
+            let state = instantiated_source.state.clone();
+            let primary = primary.downgrade();
+            let name = name.clone();
+            let status = text_overlay
+                .by_name(&status_overlay_name(&instantiated_source))
+                .map(|status| status.downgrade());
+            fallbackswitch.connect_notify(Some("active-pad"), move |fallbackswitch, _| {
+                let active = fallbackswitch.property::<Option<gstreamer::Pad>>("active-pad");
+                let on_fallback = active.is_none() || active != primary.upgrade();
+                let changed =
+                    std::mem::replace(&mut state.lock().unwrap().on_fallback, on_fallback)
+                        != on_fallback;
+                // The source is playing again, so whatever went wrong is over
+                if changed
+                    && !on_fallback
+                    && let Some(status) = status.as_ref().and_then(|status| status.upgrade())
+                {
+                    status.set_property("text", CONNECTING);
+                }
+                if changed {
+                    let structure = gstreamer::Structure::builder("source-state")
+                        .field("source", &name)
+                        .field("on-fallback", on_fallback)
+                        .build();
+                    let _ = fallbackswitch
+                        .post_message(gstreamer::message::Application::new(structure));
+                }
+            });
+        }
+        element.link_pads(Some("src"), &text_overlay, Some("sink"))?;
 
         let pad = compositor
             .static_pad(&format!("sink_{index}"))
             .expect("no pad sink_{index}");
-        text_overlay.static_pad("src").expect("no src").link(&pad)?;
+        let text_overlay_src = text_overlay.static_pad("src").expect("no src");
+        text_overlay_src.link(&pad)?;
+
+        match content {
+            Some(FallbackContent::Rtsp { rtsp }) => {
+                let secondary = secondary_source(&instantiated_source, rtsp);
+                let element = create_source(&secondary)?;
+                pipeline.add(&element)?;
+
+                let text_overlay = text_overlay.downcast_ref::<gstreamer::Bin>().expect("not a bin");
+                let secondary_sink = text_overlay
+                    .by_name("secondary")
+                    .expect("no secondary")
+                    .static_pad("sink")
+                    .expect("no sink");
+                let ghost = GhostPad::builder_with_target(&secondary_sink)?
+                    .name("secondary")
+                    .build();
+                ghost.set_active(true)?;
+                text_overlay.add_pad(&ghost)?;
+                element.static_pad("src").expect("no src").link(&ghost)?;
+                secondary.frames.watch(&ghost);
+
+                sources.insert(secondary.name.clone(), secondary);
+            }
+            Some(FallbackContent::Freeze { freeze: true }) => {
+                dim_when_frozen(&text_overlay_src, &pads[index].pad, fallback_timeout);
+            }
+            _ => {}
+        }
+
+        if let SourceType::Rtsp { rtsp, .. } = &source.source
+            && rtsp.len() > 1
+        {
+            retry_primary_periodically(&pipeline, &instantiated_source);
+        }
+
+        let border = match source.motion.as_ref().and_then(|motion| motion.highlight) {
+            Some(color) => Some(make_border(
+                &pipeline,
+                &compositor,
+                &name,
+                color,
+                cell_width,
+                cell_height,
+            )?),
+            None => None,
+        };
+        cells.add(&instantiated_source, pads[index].clone(), border);
+
+        sources.insert(name, instantiated_source);
+    }
+
+    if !config.widgets.is_empty() {
+        let layer = widgets::make_widget_layer(&config.widgets, width, height)?;
+        pipeline.add(&layer)?;
+
+        // Widgets are drawn over the whole screen, above every cell
+        let mixer = compositor
+            .downcast_ref::<gstreamer::Bin>()
+            .expect("not a bin")
+            .by_name("mixer")
+            .expect("no mixer");
+        let pad = mixer.request_pad_simple("sink_%u").expect("no pad");
+        pad.set_property("zorder", u32::MAX);
+        let ghost = GhostPad::builder_with_target(&pad)?.name("widgets").build();
+        ghost.set_active(true)?;
+        compositor.add_pad(&ghost)?;
+        layer.static_pad("src").expect("no src").link(&ghost)?;
+
+        let states = config
+            .sources
+            .iter()
+            .enumerate()
+            .map(|(index, source)| {
+                let state = sources[&format!("src_{index}")].state.clone();
+                (source.description.clone(), state)
+            })
+            .collect();
+        widgets::update_widgets(
+            &layer,
+            &config.widgets,
+            width,
+            config.display.time.as_ref(),
+            states,
+        );
     }
 
-    for pad in pads {
+    // Cells without a source, the rest are laid out by `cells`
+    for pad in pads.iter().skip(config.sources.len()) {
         pad.pad.set_property("xpos", pad.x);
         pad.pad.set_property("ypos", pad.y);
         pad.pad.set_property("width", pad.width);
         pad.pad.set_property("height", pad.height);
     }
 
-    let pipeline_clone = pipeline.clone();
-    let _guard = pipeline.bus().unwrap().add_watch(move |_, msg| {
-        match msg.view() {
-            MessageView::Error(err) => {
-                println!("Error: {}: {err:?}", err.error());
-
-                if let Some(structure) = err.structure() {
-                    if structure.name() == "GstMessageError" {
-                        if let Some(source) = err.src() {
-                            let source_name = source.name().to_string();
-                            println!("Error from source: {source_name}");
-                            if source_name.starts_with(RTSP_PREFIX) {
-                                let name = source_name.strip_prefix(RTSP_PREFIX).unwrap();
-                                let name = name.strip_suffix("_watchdog").unwrap_or(&name);
-                                let source = sources.get(name).unwrap();
-                                restart_source(&pipeline_clone, source, RestartReason::Error);
-                            }
-                        }
+    let metrics = Arc::new(metrics::PipelineMetrics::default());
+    metrics.output.watch(&sink_pad);
+
+    Ok(FramePipeline {
+        pipeline,
+        sources,
+        sink_pad,
+        cells,
+        metrics,
+    })
+}
+
+/// A solid colour input underneath a cell, which is revealed as a border when
+/// the cell is highlighted. Returns the compositor pad it's linked to.
+fn make_border(
+    pipeline: &gstreamer::Pipeline,
+    compositor: &gstreamer::Element,
+    name: &str,
+    color: labels::Color,
+    width: usize,
+    height: usize,
+) -> Result<gstreamer::Pad, Box<dyn std::error::Error>> {
+    let border = gstreamer::parse::bin_from_description(
+        &format!(
+            "videotestsrc pattern=solid-color foreground-color={}
+                ! video/x-raw,width={width},height={height},framerate=1/1",
+            color.0
+        ),
+        true,
+    )?;
+    pipeline.add(&border)?;
+
+    let mixer = compositor
+        .downcast_ref::<gstreamer::Bin>()
+        .expect("not a bin")
+        .by_name("mixer")
+        .expect("no mixer");
+    let pad = mixer.request_pad_simple("sink_%u").expect("no pad");
+    pad.set_property("alpha", 0.0f64);
+    let ghost = GhostPad::builder_with_target(&pad)?
+        .name(format!("{name}_border"))
+        .build();
+    ghost.set_active(true)?;
+    compositor.add_pad(&ghost)?;
+    border.static_pad("src").expect("no src").link(&ghost)?;
+    Ok(pad)
+}
+
+/// Find the source whose bin contains the given object, if any.
+fn owning_source<'a>(
+    sources: &'a HashMap<String, InstantiatedSource>,
+    object: &gstreamer::Object,
+) -> Option<&'a InstantiatedSource> {
+    let mut object = Some(object.clone());
+    while let Some(current) = object {
+        if let Some(source) = sources.get(current.name().as_str()) {
+            return Some(source);
+        }
+        object = current.parent();
+    }
+    None
+}
+
+/// A failure that can't be recovered by restarting a single source.
+#[derive(Debug)]
+struct PipelineFailure(String);
+
+/// Something that happened in a source, for reacting to outside of the pipeline
+#[derive(Debug, Clone)]
+enum SourceEvent {
+    /// The source's video went away or came back
+    State { source: String, on_fallback: bool },
+    Restarted {
+        source: String,
+        reason: String,
+    },
+    Motion {
+        source: String,
+        /// The fraction of the picture that changed
+        level: f64,
+        /// Whether the source wants the display turned on
+        wake: bool,
+    },
+}
+
+fn handle_bus_message(
+    pipeline: &gstreamer::Pipeline,
+    sources: &HashMap<String, InstantiatedSource>,
+    metrics: &metrics::PipelineMetrics,
+    msg: &gstreamer::Message,
+) -> Result<Option<SourceEvent>, PipelineFailure> {
+    // Messages from within a source, or about one, are logged in its context
+    let source = msg.src().and_then(|src| owning_source(sources, src)).or_else(|| {
+        let MessageView::Application(application) = msg.view() else {
+            return None;
+        };
+        let source = application.structure()?.get::<String>("source").ok()?;
+        sources.get(&source)
+    });
+    let _span = source.map(|source| source.span().entered());
+
+    match msg.view() {
+        MessageView::Error(err) => {
+            let Some(src) = err.src() else {
+                error!("Error: {}: {err:?}", err.error());
+                return Err(PipelineFailure(err.error().to_string()));
+            };
+            let source_name = src.name().to_string();
+            error!(element = source_name, "Error: {}: {err:?}", err.error());
+            match source {
+                Some(source) => match source.source.source {
+                    SourceType::Rtsp { .. } => {
+                        restart_source(pipeline, source, RestartReason::Error);
+                    }
+                    _ => {
+                        warn!("Ignoring error in non-RTSP source");
                     }
+                },
+                None => {
+                    return Err(PipelineFailure(format!("{source_name}: {}", err.error())));
                 }
             }
-            MessageView::StateChanged(state) => {
-                // Check for interesting state changes: rtspsrc*, pi-frame
-                if let Some(src) = state.src() {
-                    let name = src.name();
-                    if name.starts_with(RTSP_PREFIX) || name == "pi-frame" {
-                        // pipeline_clone.debug_to_dot_file(gstreamer::DebugGraphDetails::all(), "pipeline");
-                        if state.old() != gstreamer::State::Null {
-                            println!(
-                                "State changed [{name:?}]: {:?} -> {:?}",
-                                state.old(),
-                                state.current()
-                            );
-                        }
+        }
+        MessageView::StateChanged(state) => {
+            // Check for interesting state changes: rtspsrc*, pi-frame
+            if let Some(src) = state.src() {
+                let name = src.name();
+                if name.starts_with(RTSP_PREFIX) || name == "pi-frame" {
+                    if state.old() != gstreamer::State::Null {
+                        debug!(
+                            "State changed [{name:?}]: {:?} -> {:?}",
+                            state.old(),
+                            state.current()
+                        );
                     }
                 }
             }
-            MessageView::Element(element) => {
-                if let Some(structure) = element.structure() {
-                    if structure.name() == "GstRTSPSrcTimeout" {
-                        if let Some(src) = element.src() {
-                            let name = src.name().to_string();
-                            println!("RTSP timeout on source: {name}");
-                            let name = name.strip_prefix(RTSP_PREFIX).unwrap();
-                            let source = sources.get(name).unwrap();
-                            restart_source(&pipeline_clone, source, RestartReason::Timeout);
-                        }
-                    } else if structure.name().contains("Timeout") {
-                        println!("Timeout on element: {:?}", element);
+        }
+        MessageView::Element(element) => {
+            if let Some(structure) = element.structure() {
+                if structure.name() == "GstRTSPSrcTimeout" {
+                    if let Some(src) = element.src() {
+                        let name = src.name().to_string();
+                        warn!("RTSP timeout on source: {name}");
+                        let name = name.strip_prefix(RTSP_PREFIX).unwrap();
+                        let source = sources.get(name).unwrap();
+                        restart_source(pipeline, source, RestartReason::Timeout);
                     }
+                } else if structure.name() == "motion" {
+                    if let Some(source) = source {
+                        let level = structure.get::<f64>("level").unwrap_or_default();
+                        info!(level, "Motion on source ({:.1}%)", level * 100.0);
+                        let motion = source.source.motion.as_ref();
+                        let wake = motion.is_some_and(|motion| motion.wake);
+                        return Ok(Some(SourceEvent::Motion {
+                            source: source.name.clone(),
+                            level,
+                            wake,
+                        }));
+                    }
+                } else if structure.name().contains("Timeout") {
+                    warn!("Timeout on element: {:?}", element);
                 }
             }
-            MessageView::StreamStatus(status) => {
-                if let Some(_src) = status.src() {
-                    if let Some(structure) = status.structure() {
-                        if let Ok(status_type) = structure.value("type") {
-                            // Coercse status_type to String
-                            let status_type_string = format!("{:?}", status_type);
-                            if status_type_string.contains("GST_STREAM_STATUS_TYPE_CREATE")
-                                || status_type_string.contains("GST_STREAM_STATUS_TYPE_ENTER")
-                                || status_type_string.contains("GST_STREAM_STATUS_TYPE_LEAVE")
-                            {
-                                // ignore
-                            } else {
-                                println!("Stream status: {:?}", structure);
-                            }
+        }
+        MessageView::StreamStatus(status) => {
+            if let Some(_src) = status.src() {
+                if let Some(structure) = status.structure() {
+                    if let Ok(status_type) = structure.value("type") {
+                        // Coercse status_type to String
+                        let status_type_string = format!("{:?}", status_type);
+                        if status_type_string.contains("GST_STREAM_STATUS_TYPE_CREATE")
+                            || status_type_string.contains("GST_STREAM_STATUS_TYPE_ENTER")
+                            || status_type_string.contains("GST_STREAM_STATUS_TYPE_LEAVE")
+                        {
+                            // ignore
+                        } else {
+                            debug!("Stream status: {:?}", structure);
                         }
                     }
                 }
             }
-            MessageView::Eos(element) => {
-                println!("EOS on element: {:?}", element);
-            }
-            MessageView::Qos(qos) => {
-                if let Some(src) = qos.src() {
-                    let name = src.name().to_string();
-                    // println!("QoS: {name:?} {:?} {:?} {:?}", qos.stats(), qos.values(), qos.get());
-                }
+        }
+        MessageView::Eos(element) => {
+            error!("EOS on element: {:?}", element);
+            return Err(PipelineFailure("unexpected end of stream".to_string()));
+        }
+        MessageView::Qos(qos) => {
+            if let Some(src) = qos.src() {
+                metrics.record_qos(src, owning_source(sources, src), qos);
             }
-            MessageView::Latency(latency) => {
-                // Ignored...
+        }
+        MessageView::Latency(latency) => {
+            // Ignored...
+        }
+        MessageView::Progress(progress) => {
+            if let Some(src) = progress.src() {
+                let name = src.name().to_string();
+                debug!("Progress: {name:?} {:?}", progress.get());
             }
-            MessageView::Progress(progress) => {
-                if let Some(src) = progress.src() {
-                    let name = src.name().to_string();
-                    println!("Progress: {name:?} {:?}", progress.get());
+        }
+        MessageView::Application(application) => {
+            let Some(structure) = application.structure() else {
+                return Ok(None);
+            };
+            let Ok(source) = structure.get::<String>("source") else {
+                return Ok(None);
+            };
+            match structure.name().as_str() {
+                "source-state" => {
+                    let on_fallback = structure.get::<bool>("on-fallback").unwrap_or(true);
+                    if on_fallback {
+                        warn!("Source is offline");
+                    } else {
+                        info!("Source is up");
+                    }
+                    return Ok(Some(SourceEvent::State {
+                        source,
+                        on_fallback,
+                    }));
                 }
+                "restart" => {
+                    let reason = structure.get::<String>("reason").unwrap_or_default();
+                    return Ok(Some(SourceEvent::Restarted { source, reason }));
+                }
+                _ => {}
             }
-            _ => {
-                println!("Message: {:?}", msg.view());
+        }
+        _ => {
+            trace!("Message: {:?}", msg.view());
+        }
+    }
+    Ok(None)
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let mut args = std::env::args().skip(1);
+    match args.next().as_deref() {
+        Some("render-splash") => splash::render_splash_command(args),
+        Some("show") => fbimage::show_command(args),
+        Some(config_file) => run(Path::new(config_file)),
+        None => Err("Usage: pi-frame <config.toml> | render-splash <image> ... | show <image> ...".into()),
+    }
+}
+
+/// Read the config file, resolving any paths relative to it.
+fn load_config(config_file: &Path) -> Result<Config, Box<dyn std::error::Error>> {
+    let config_dir = config_file.parent().unwrap().to_owned();
+    let mut config = toml::from_str::<Config>(std::fs::read_to_string(config_file)?.as_str())?;
+
+    // Resolve image paths
+    let resolve = |image: &mut String| -> std::io::Result<()> {
+        let path = config_dir.join(&image);
+        // Sequences are patterns, so only their directory exists
+        let path = match (is_image_sequence(image), path.parent(), path.file_name()) {
+            (true, Some(parent), Some(name)) => parent.canonicalize()?.join(name),
+            _ => path.canonicalize()?,
+        };
+        *image = path
+            .to_str()
+            .expect("image path is not valid")
+            .to_string();
+        Ok(())
+    };
+    for source in &mut config.sources {
+        if let Some(image) = source.placeholder.take() {
+            if source.fallback.content.is_some() {
+                return Err(format!(
+                    "{:?} has both a placeholder and fallback content, use only fallback",
+                    source.description
+                )
+                .into());
             }
+            source.fallback.content = Some(FallbackContent::Image { image });
         }
-        glib::ControlFlow::Continue
-    })?;
-
-    // std::thread::spawn(move || {
-    //     loop {
-    //         snapshotter.request(|image| {
-    //             match image {
-    //                 Ok(image) => match std::fs::write("target/frame.jpg", image) {
-    //                     Ok(_) => {},
-    //                     Err(e) => eprintln!("Failed to write snapshot: {e:?}"),
-    //                 },
-    //                 Err(e) => eprintln!("Failed to take snapshot: {e:?}"),
-    //             }
-    //         });
-    //         println!("Received frame");
-    //         std::thread::sleep(std::time::Duration::from_secs(10));
-    //     }
-    // });
-
-    pipeline.set_state(gstreamer::State::Playing)?;
+        // Frozen frames never switch away from the source, so the label would
+        // never show
+        let freeze = matches!(
+            source.fallback.content,
+            Some(FallbackContent::Freeze { freeze: true })
+        );
+        if freeze && source.label.or(&config.display.labels).only_on_fallback() {
+            return Err(format!(
+                "{:?} has only_on_fallback labels, which can't be used with fallback = {{ freeze = true }}",
+                source.description
+            )
+            .into());
+        }
+        // Paused sources can't see any motion, so they'd never wake the display
+        if config.schedule.pauses_sources()
+            && source.motion.as_ref().is_some_and(|motion| motion.wake)
+        {
+            return Err(format!(
+                "{:?} has motion wake, which can't be used with [schedule] pause_sources, set wake = false",
+                source.description
+            )
+            .into());
+        }
+        match &mut source.source {
+            SourceType::Image { image, .. } => resolve(image)?,
+            _ => {}
+        }
+        if let Some(FallbackContent::Image { image }) = &mut source.fallback.content {
+            check_placeholder(image)?;
+            resolve(image)?;
+        }
+    }
+    if let Some(image) = &mut config.display.startup_image {
+        resolve(image)?;
+    }
+    if let Some(image) = &mut config.display.shutdown_image {
+        resolve(image)?;
+    }
+    if let Some(placeholder) = &mut config.display.placeholder {
+        check_placeholder(placeholder)?;
+        resolve(placeholder)?;
+    }
+    for widget in &mut config.widgets {
+        widget.resolve_paths(&config_dir);
+    }
+    config.graphs.resolve_paths(&config_dir);
+    if let Some(snapshots) = &mut config.snapshots {
+        snapshots.prepare(&config_dir)?;
+    }
+    if let Some(restream) = &config.restream {
+        restream.prepare()?;
+    }
+    Ok(config)
+}
+
+fn run(config_file: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let config_file = config_file.canonicalize()?;
+    let config = load_config(&config_file)?;
+    logging::init(&config.logging)?;
+    if let Some(time) = &config.display.time {
+        time.set_locale()?;
+    }
+
+    // Set up main loop
+    let main_loop = glib::MainLoop::new(None, false);
+
+    // Initialize GStreamer
+    gstreamer::init()?;
+
+    gstfallbackswitch::plugin_register_static()?;
+
+    let mut framebuffer = framebuffer::Framebuffer::new(&config.display.framebuffer)?;
+    let (width, height) = (
+        framebuffer.var_screen_info.xres,
+        framebuffer.var_screen_info.yres,
+    );
+    info!("Framebuffer size: {width}x{height}");
+    debug!("Display var_screen_info: {:?}", framebuffer.var_screen_info);
+
+    // Clear the framebuffer in debug mode
+    if std::env::var("CLEAR_FRAMEBUFFER").is_ok() {
+        let frame = framebuffer.read_frame();
+        let zeros = vec![0; frame.len()];
+        framebuffer.write_frame(&zeros);
+    }
+
+    if let Some(startup_image) = &config.display.startup_image {
+        let image = fbimage::load(startup_image)
+            .map_err(|e| format!("can't load startup image {startup_image}: {e}"))?;
+        fbimage::draw(&mut framebuffer, &image);
+    }
+
+    drop(framebuffer);
+
+    let framebuffer_path = config.display.framebuffer.clone();
+    // Loaded now, so a bad image is found before anything's started
+    let shutdown_image = config
+        .display
+        .shutdown_image
+        .as_ref()
+        .map(|path| {
+            fbimage::load(path).map_err(|e| format!("can't load shutdown image {path}: {e}"))
+        })
+        .transpose()?;
+    let schedule = config.schedule.clone();
+    let mqtt_config = config.mqtt.clone();
+    let api_config = config.api.clone();
+    let snapshot_config = config.snapshots.clone();
+    let rtsp_config = config
+        .restream
+        .as_ref()
+        .and_then(|restream| restream.rtsp.clone());
+    let button_config = config.intercom.button.clone();
+
+    let supervisor = supervisor::Supervisor::start(config, width as _, height as _)?;
+    let scheduler = schedule::Scheduler::start(
+        schedule,
+        framebuffer_path.clone().into(),
+        supervisor.handle(),
+    )?;
+    {
+        let scheduler = scheduler.handle();
+        supervisor.handle().connect(move |event| {
+            if let SourceEvent::Motion { wake: true, .. } = event {
+                scheduler.wake();
+            }
+        });
+    }
+
+    let commands = control::Controller {
+        config_file: config_file.clone(),
+        supervisor: supervisor.handle(),
+        scheduler: scheduler.handle(),
+    }
+    .start();
+
+    if let Some(mqtt_config) = mqtt_config {
+        let mqtt = mqtt::Mqtt::start(&mqtt_config, commands.clone());
+        supervisor
+            .handle()
+            .connect(move |event| mqtt.publish_event(event));
+    }
+
+    if let Some(api_config) = api_config {
+        api::start(&api_config, commands.clone())?;
+    }
+
+    if let Some(rtsp_config) = rtsp_config {
+        restream::start_server(&rtsp_config)?;
+    }
+
+    if let Some(button_config) = button_config {
+        intercom::watch_button(&button_config, commands.clone())?;
+    }
+
+    // Only map the framebuffer a second time if it's going to be read
+    let snapshotter = match snapshot_config {
+        Some(snapshot_config) => {
+            let snapshotter =
+                Rc::new(snapshots::Snapshotter::start(Path::new(&framebuffer_path))?);
+            snapshots::start(
+                snapshot_config,
+                Rc::downgrade(&snapshotter),
+                supervisor.handle(),
+            );
+            Some(snapshotter)
+        }
+        None => None,
+    };
+
+    {
+        let supervisor = supervisor.handle();
+        glib::unix_signal_add_local(libc::SIGUSR1, move || {
+            info!("Received SIGUSR1, dumping the pipeline graph");
+            let _ = supervisor.dump_graph("signal");
+            glib::ControlFlow::Continue
+        });
+    }
+
+    for signal in [libc::SIGTERM, libc::SIGINT] {
+        let main_loop = main_loop.clone();
+        glib::unix_signal_add_local(signal, move || {
+            info!("Received signal {signal}, shutting down");
+            main_loop.quit();
+            glib::ControlFlow::Break
+        });
+    }
 
     main_loop.run();
+    supervisor.shutdown();
+    drop(scheduler);
+    drop(snapshotter);
+
+    if let Some(image) = shutdown_image {
+        let mut framebuffer = framebuffer::Framebuffer::new(&framebuffer_path)?;
+        fbimage::draw(&mut framebuffer, &image);
+    }
 
     Ok(())
 }
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn streams(sizes: &[Option<(usize, usize)>]) -> Vec<RtspStream> {
+        sizes
+            .iter()
+            .enumerate()
+            .map(|(index, size)| RtspStream {
+                url: format!("rtsp://camera/{index}"),
+                size: *size,
+            })
+            .collect()
+    }
+
+    fn failover(failures: usize, window: u64, retry_primary: u64) -> Failover {
+        Failover {
+            failures,
+            window,
+            retry_primary,
+        }
+    }
+
+    #[test]
+    fn selects_smallest_big_enough_stream() {
+        let urls = streams(&[Some((1920, 1080)), Some((640, 360)), Some((1280, 720))]);
+        assert_eq!(select_stream(&urls, 640, 400), 2);
+        assert_eq!(select_stream(&urls, 320, 180), 1);
+        assert_eq!(select_stream(&urls, 1280, 720), 2);
+        // Nothing's big enough, so the biggest
+        assert_eq!(select_stream(&urls, 2560, 1440), 0);
+    }
+
+    #[test]
+    fn selects_sized_streams_over_unsized() {
+        assert_eq!(
+            select_stream(&streams(&[None, Some((640, 360))]), 1920, 1080),
+            1
+        );
+        assert_eq!(select_stream(&streams(&[None, None]), 640, 360), 0);
+        assert_eq!(select_stream(&[], 640, 360), 0);
+    }
+
+    #[test]
+    fn resizes_on_the_only_stream() {
+        let urls = streams(&[Some((1280, 720))]);
+        assert_eq!(
+            stream_for_resize(&urls, 0, (640, 400), (1280, 800)),
+            Some(0)
+        );
+        assert_eq!(
+            stream_for_resize(&urls, 0, (1280, 800), (640, 400)),
+            Some(0)
+        );
+        assert_eq!(stream_for_resize(&urls, 0, (640, 400), (640, 400)), None);
+    }
+
+    #[test]
+    fn resizes_onto_a_bigger_stream() {
+        let urls = streams(&[Some((1920, 1080)), Some((640, 360))]);
+        assert_eq!(
+            stream_for_resize(&urls, 1, (640, 360), (1280, 800)),
+            Some(0)
+        );
+        assert_eq!(
+            stream_for_resize(&urls, 0, (1280, 800), (640, 360)),
+            Some(1)
+        );
+    }
+
+    #[test]
+    fn fails_over_after_repeated_failures() {
+        let urls = streams(&[None, None]);
+        let failover = failover(3, 60, 300);
+        let mut selection = StreamSelection::new(0);
+        selection.record_failure(&failover, &urls);
+        selection.record_failure(&failover, &urls);
+        assert_eq!(selection.index, 0);
+        selection.record_failure(&failover, &urls);
+        assert_eq!(selection.index, 1);
+        assert!(selection.failures.is_empty());
+
+        // And round again
+        for _ in 0..3 {
+            selection.record_failure(&failover, &urls);
+        }
+        assert_eq!(selection.index, 0);
+    }
+
+    #[test]
+    fn forgets_failures_outside_the_window() {
+        let urls = streams(&[None, None]);
+        let failover = failover(2, 0, 300);
+        let mut selection = StreamSelection::new(0);
+        selection.record_failure(&failover, &urls);
+        selection.record_failure(&failover, &urls);
+        assert_eq!(selection.index, 0);
+    }
+
+    #[test]
+    fn stays_on_the_only_url() {
+        let urls = streams(&[None]);
+        let failover = failover(1, 60, 300);
+        let mut selection = StreamSelection::new(0);
+        for _ in 0..5 {
+            selection.record_failure(&failover, &urls);
+        }
+        assert_eq!(selection.index, 0);
+    }
+
+    #[test]
+    fn retries_primary_after_a_while() {
+        let urls = streams(&[None, None]);
+        let mut selection = StreamSelection::new(0);
+        assert!(!selection.should_retry_primary(&failover(1, 60, 0)));
+
+        selection.record_failure(&failover(1, 60, 0), &urls);
+        assert_eq!(selection.index, 1);
+        assert!(!selection.should_retry_primary(&failover(1, 60, 300)));
+        assert!(selection.should_retry_primary(&failover(1, 60, 0)));
+    }
+
+    #[test]
+    fn goes_back_when_primary_still_fails() {
+        let urls = streams(&[None, None, None]);
+        let failover = failover(1, 60, 0);
+        let mut selection = StreamSelection::new(0);
+        selection.record_failure(&failover, &urls);
+        selection.record_failure(&failover, &urls);
+        assert_eq!(selection.index, 2);
+
+        selection.retry_primary();
+        assert_eq!(selection.index, 0);
+        // Not until the retry has succeeded or failed
+        assert!(!selection.should_retry_primary(&failover));
+        selection.record_failure(&failover, &urls);
+        assert_eq!(selection.index, 2);
+        assert!(selection.probing.is_none());
+    }
+
+    #[test]
+    fn keeps_primary_once_it_survives_the_window() {
+        let urls = streams(&[None, None]);
+        let mut selection = StreamSelection::new(0);
+        selection.record_failure(&failover(1, 60, 0), &urls);
+        selection.retry_primary();
+
+        // Survived a zero second window
+        let failover = failover(1, 0, 0);
+        assert!(!selection.should_retry_primary(&failover));
+        assert!(selection.probing.is_none());
+        assert!(!selection.should_retry_primary(&failover));
+        assert_eq!(selection.index, 0);
+    }
+}
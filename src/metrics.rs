@@ -0,0 +1,346 @@
+//! Prometheus metrics for the running pipeline, served by the API at `/metrics`.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Write,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use gstreamer::prelude::*;
+
+use crate::{InstantiatedSource, RTSP_PREFIX};
+
+/// How long without a frame before a frame rate is reported as zero
+const STALE: Duration = Duration::from_secs(2);
+
+/// Counts the buffers passing a pad. Cheap to clone.
+#[derive(Debug, Clone, Default)]
+pub struct FrameCounter(Arc<Mutex<Frames>>);
+
+#[derive(Debug, Default)]
+struct Frames {
+    total: u64,
+    last: Option<Instant>,
+    /// Frames since `window_start`, for the frame rate
+    window_start: Option<Instant>,
+    window_frames: u64,
+    fps: f64,
+}
+
+impl FrameCounter {
+    /// Count every buffer passing `pad`.
+    pub fn watch(&self, pad: &impl IsA<gstreamer::Pad>) {
+        let counter = self.clone();
+        pad.add_probe(gstreamer::PadProbeType::BUFFER, move |_pad, _info| {
+            counter.tick();
+            gstreamer::PadProbeReturn::Ok
+        });
+    }
+
+    fn tick(&self) {
+        let now = Instant::now();
+        let mut frames = self.0.lock().unwrap();
+        frames.total += 1;
+        frames.last = Some(now);
+        frames.window_frames += 1;
+        let start = *frames.window_start.get_or_insert(now);
+        let elapsed = now - start;
+        if elapsed >= Duration::from_secs(1) {
+            frames.fps = frames.window_frames as f64 / elapsed.as_secs_f64();
+            frames.window_start = Some(now);
+            frames.window_frames = 0;
+        }
+    }
+
+    fn total(&self) -> u64 {
+        self.0.lock().unwrap().total
+    }
+
+    fn fps(&self) -> f64 {
+        let frames = self.0.lock().unwrap();
+        match frames.last {
+            Some(last) if last.elapsed() < STALE => frames.fps,
+            _ => 0.0,
+        }
+    }
+
+    fn since_last(&self) -> Option<f64> {
+        let frames = self.0.lock().unwrap();
+        frames.last.map(|last| last.elapsed().as_secs_f64())
+    }
+}
+
+/// Metrics for the pipeline as a whole, collected from its probes and bus.
+#[derive(Default)]
+pub struct PipelineMetrics {
+    /// Frames reaching the framebuffer
+    pub output: FrameCounter,
+    /// The latest QoS stats from each element that has posted them
+    qos: Mutex<BTreeMap<String, Qos>>,
+}
+
+struct Qos {
+    source: Option<String>,
+    processed: u64,
+    dropped: u64,
+}
+
+impl PipelineMetrics {
+    /// Record the stats from a QoS message, which are running totals for the
+    /// element that posted it.
+    pub fn record_qos(
+        &self,
+        element: &gstreamer::Object,
+        source: Option<&InstantiatedSource>,
+        qos: &gstreamer::message::Qos,
+    ) {
+        let (processed, dropped) = qos.stats();
+        let qos = Qos {
+            source: source.map(|source| source.name.clone()),
+            processed: processed.value().max(0) as u64,
+            dropped: dropped.value().max(0) as u64,
+        };
+        let element = element.path_string().to_string();
+        self.qos.lock().unwrap().insert(element, qos);
+    }
+
+    /// Render everything in the Prometheus text format.
+    pub fn render(
+        &self,
+        pipeline: &gstreamer::Pipeline,
+        sources: &HashMap<String, InstantiatedSource>,
+    ) -> String {
+        let mut sources = sources.values().collect::<Vec<_>>();
+        sources.sort_by_key(|source| (source.index, source.name.clone()));
+        // Secondary streams don't have cells of their own
+        let cells = sources
+            .iter()
+            .filter(|source| !source.name.ends_with("_secondary"))
+            .collect::<Vec<_>>();
+        let mut out = String::new();
+
+        family(
+            &mut out,
+            "pi_frame_source_info",
+            "gauge",
+            "Each source, with its description",
+            sources.iter().map(|source| {
+                let labels = labels(&[
+                    ("source", &source.name),
+                    ("description", &source.source.description),
+                ]);
+                (labels, 1.0)
+            }),
+        );
+        family(
+            &mut out,
+            "pi_frame_source_restarts_total",
+            "counter",
+            "Source restarts, by reason",
+            sources.iter().flat_map(|source| {
+                let restarts = source.state.lock().unwrap().restarts.clone();
+                restarts.into_iter().map(|(reason, count)| {
+                    let labels = labels(&[("source", &source.name), ("reason", reason)]);
+                    (labels, count as f64)
+                })
+            }),
+        );
+        family(
+            &mut out,
+            "pi_frame_source_fallback_active",
+            "gauge",
+            "Whether the source's fallback is shown instead of its video",
+            cells.iter().map(|source| {
+                let on_fallback = source.state.lock().unwrap().on_fallback;
+                (source_labels(source), if on_fallback { 1.0 } else { 0.0 })
+            }),
+        );
+        family(
+            &mut out,
+            "pi_frame_source_frames_total",
+            "counter",
+            "Frames decoded from the source",
+            sources
+                .iter()
+                .map(|source| (source_labels(source), source.frames.total() as f64)),
+        );
+        family(
+            &mut out,
+            "pi_frame_source_fps",
+            "gauge",
+            "Frames per second decoded from the source",
+            sources
+                .iter()
+                .map(|source| (source_labels(source), source.frames.fps())),
+        );
+        family(
+            &mut out,
+            "pi_frame_source_seconds_since_frame",
+            "gauge",
+            "Seconds since the source last produced a frame",
+            sources.iter().filter_map(|source| {
+                let since = source.frames.since_last()?;
+                Some((source_labels(source), since))
+            }),
+        );
+
+        let qos = self.qos.lock().unwrap();
+        let qos_labels = |element: &str, qos: &Qos| match &qos.source {
+            Some(source) => labels(&[("element", element), ("source", source)]),
+            None => labels(&[("element", element)]),
+        };
+        family(
+            &mut out,
+            "pi_frame_qos_processed_frames_total",
+            "counter",
+            "Frames processed by elements posting QoS messages",
+            qos.iter()
+                .map(|(element, qos)| (qos_labels(element, qos), qos.processed as f64)),
+        );
+        family(
+            &mut out,
+            "pi_frame_qos_dropped_frames_total",
+            "counter",
+            "Frames dropped for being late, by elements posting QoS messages",
+            qos.iter()
+                .map(|(element, qos)| (qos_labels(element, qos), qos.dropped as f64)),
+        );
+        drop(qos);
+
+        let jitterbuffers = sources
+            .iter()
+            .flat_map(|source| {
+                jitterbuffer_stats(pipeline, source)
+                    .into_iter()
+                    .map(move |(element, stats)| {
+                        let labels =
+                            labels(&[("source", &source.name), ("jitterbuffer", &element)]);
+                        (labels, stats)
+                    })
+            })
+            .collect::<Vec<_>>();
+        for (name, kind, help, field, scale) in [
+            (
+                "pi_frame_rtp_packets_total",
+                "counter",
+                "RTP packets received",
+                "num-pushed",
+                1.0,
+            ),
+            (
+                "pi_frame_rtp_packets_lost_total",
+                "counter",
+                "RTP packets lost",
+                "num-lost",
+                1.0,
+            ),
+            (
+                "pi_frame_rtp_packets_late_total",
+                "counter",
+                "RTP packets arriving too late to be used",
+                "num-late",
+                1.0,
+            ),
+            (
+                "pi_frame_rtp_jitter_seconds",
+                "gauge",
+                "Average RTP packet jitter",
+                "avg-jitter",
+                1e-9,
+            ),
+        ] {
+            family(
+                &mut out,
+                name,
+                kind,
+                help,
+                jitterbuffers.iter().filter_map(|(labels, stats)| {
+                    let value = stats.get::<u64>(field).ok()?;
+                    Some((labels.clone(), value as f64 * scale))
+                }),
+            );
+        }
+
+        family(
+            &mut out,
+            "pi_frame_output_frames_total",
+            "counter",
+            "Frames written to the framebuffer",
+            [(String::new(), self.output.total() as f64)],
+        );
+        family(
+            &mut out,
+            "pi_frame_output_fps",
+            "gauge",
+            "Frames per second written to the framebuffer",
+            [(String::new(), self.output.fps())],
+        );
+
+        out
+    }
+}
+
+/// The stats of each of an RTSP source's jitterbuffers, by element name.
+fn jitterbuffer_stats(
+    pipeline: &gstreamer::Pipeline,
+    source: &InstantiatedSource,
+) -> Vec<(String, gstreamer::Structure)> {
+    let Some(rtspsrc) = pipeline.by_name(&format!("{RTSP_PREFIX}{}", source.name)) else {
+        return vec![];
+    };
+    let Some(rtspsrc) = rtspsrc.downcast_ref::<gstreamer::Bin>() else {
+        return vec![];
+    };
+    rtspsrc
+        .iterate_recurse()
+        .into_iter()
+        .filter_map(Result::ok)
+        .filter(|element| {
+            element
+                .factory()
+                .is_some_and(|factory| factory.name() == "rtpjitterbuffer")
+        })
+        .map(|element| {
+            let stats = element.property::<gstreamer::Structure>("stats");
+            (element.name().to_string(), stats)
+        })
+        .collect()
+}
+
+fn source_labels(source: &InstantiatedSource) -> String {
+    labels(&[("source", &source.name)])
+}
+
+fn labels(labels: &[(&str, &str)]) -> String {
+    let labels = labels
+        .iter()
+        .map(|(name, value)| {
+            let value = value
+                .replace('\\', r"\\")
+                .replace('"', r#"\""#)
+                .replace('\n', r"\n");
+            format!(r#"{name}="{value}""#)
+        })
+        .collect::<Vec<_>>();
+    format!("{{{}}}", labels.join(","))
+}
+
+/// Write one metric family, skipping it entirely if it has no samples.
+fn family(
+    out: &mut String,
+    name: &str,
+    kind: &str,
+    help: &str,
+    samples: impl IntoIterator<Item = (String, f64)>,
+) {
+    let mut samples = samples.into_iter().peekable();
+    if samples.peek().is_none() {
+        return;
+    }
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+    for (labels, value) in samples {
+        let _ = writeln!(out, "{name}{labels} {value}");
+    }
+}
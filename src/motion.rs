@@ -0,0 +1,215 @@
+//! Cheap motion detection, by differencing small greyscale copies of a
+//! source's decoded frames.
+
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use gstreamer::prelude::*;
+use serde::Deserialize;
+
+use crate::labels::Color;
+
+/// The size frames are scaled down to before comparing them. The width is a
+/// multiple of 4, so GRAY8 rows aren't padded.
+const WIDTH: usize = 64;
+const HEIGHT: usize = 48;
+const MAX_RATE: u32 = 5;
+/// Minimum time between motion messages while motion continues
+const REPEAT: Duration = Duration::from_secs(1);
+
+/// A source's `motion` table
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Motion {
+    /// How much a pixel's brightness must change, out of 255, to count
+    pub pixel_threshold: u8,
+    /// The fraction of the unmasked picture that must change. Lower is more
+    /// sensitive.
+    pub threshold: f64,
+    /// Regions to ignore, as `[x, y, width, height]` fractions of the picture
+    pub mask: Vec<[f64; 4]>,
+    /// Turn the display back on if it's been turned off by the schedule
+    pub wake: bool,
+    /// Draw a border of this colour around the cell
+    pub highlight: Option<Color>,
+    /// Show the source full-screen
+    pub focus: bool,
+    /// Seconds to keep the highlight or focus after motion stops
+    pub hold: u64,
+}
+
+impl Default for Motion {
+    fn default() -> Self {
+        Self {
+            pixel_threshold: 25,
+            threshold: 0.02,
+            mask: vec![],
+            wake: false,
+            highlight: None,
+            focus: false,
+            hold: 10,
+        }
+    }
+}
+
+impl Motion {
+    /// The pipeline description for a detector fed from the tee named `tee`,
+    /// ending back at the tee so the main branch can carry on from it.
+    pub fn branch_description(tee: &str, detector: &str) -> String {
+        format!(
+            r#"! tee name={tee:?}
+                {tee}. ! queue leaky=downstream max-size-buffers=1
+                ! videorate drop-only=true max-rate={MAX_RATE}
+                ! videoconvertscale add-borders=false
+                ! video/x-raw,format=GRAY8,width={WIDTH},height={HEIGHT},pixel-aspect-ratio=1/1
+                ! fakesink name={detector:?} sync=false async=false
+                {tee}. "#
+        )
+    }
+
+    fn masked(&self) -> Vec<bool> {
+        let mut masked = vec![false; WIDTH * HEIGHT];
+        for [x, y, width, height] in &self.mask {
+            let left = (x * WIDTH as f64) as usize;
+            let top = (y * HEIGHT as f64) as usize;
+            let right = (((x + width) * WIDTH as f64).ceil() as usize).min(WIDTH);
+            let bottom = (((y + height) * HEIGHT as f64).ceil() as usize).min(HEIGHT);
+            for row in top..bottom {
+                for column in left..right {
+                    masked[row * WIDTH + column] = true;
+                }
+            }
+        }
+        masked
+    }
+
+    /// The fraction of unmasked pixels whose brightness changed by more than
+    /// `pixel_threshold` between two frames.
+    fn level(frame: &[u8], previous: &[u8], masked: &[bool], pixel_threshold: u8) -> f64 {
+        let unmasked = masked.iter().filter(|masked| !**masked).count();
+        let changed = frame
+            .iter()
+            .zip(previous)
+            .zip(masked)
+            .filter(|((a, b), masked)| !**masked && a.abs_diff(**b) > pixel_threshold)
+            .count();
+        changed as f64 / unmasked as f64
+    }
+
+    /// Compare each frame reaching the detector with the one before it, and
+    /// post a `motion` element message from the detector when enough changed.
+    pub fn watch(&self, detector: &gstreamer::Element) {
+        let masked = self.masked();
+        if masked.iter().all(|masked| *masked) {
+            return;
+        }
+        let pixel_threshold = self.pixel_threshold;
+        let threshold = self.threshold;
+        // The previous frame, and when motion was last reported
+        let state = Mutex::new((None::<Vec<u8>>, None::<Instant>));
+
+        let pad = detector.static_pad("sink").expect("no sink");
+        pad.add_probe(gstreamer::PadProbeType::BUFFER, move |pad, info| {
+            let Some(buffer) = info.buffer() else {
+                return gstreamer::PadProbeReturn::Ok;
+            };
+            let Ok(map) = buffer.map_readable() else {
+                return gstreamer::PadProbeReturn::Ok;
+            };
+            if map.len() < WIDTH * HEIGHT {
+                return gstreamer::PadProbeReturn::Ok;
+            }
+            let frame = &map[..WIDTH * HEIGHT];
+
+            let mut state = state.lock().unwrap();
+            let (previous, last_motion) = &mut *state;
+            if let Some(previous) = previous.as_ref() {
+                let level = Self::level(frame, previous, &masked, pixel_threshold);
+                let repeat = last_motion.is_none_or(|last| last.elapsed() >= REPEAT);
+                if level >= threshold && repeat {
+                    *last_motion = Some(Instant::now());
+                    if let Some(detector) = pad.parent_element() {
+                        let structure = gstreamer::Structure::builder("motion")
+                            .field("level", level)
+                            .build();
+                        let message = gstreamer::message::Element::builder(structure)
+                            .src(&detector)
+                            .build();
+                        let _ = detector.post_message(message);
+                    }
+                }
+            }
+            match previous {
+                Some(previous) => previous.copy_from_slice(frame),
+                None => *previous = Some(frame.to_vec()),
+            }
+            gstreamer::PadProbeReturn::Ok
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn masking(mask: Vec<[f64; 4]>) -> Vec<bool> {
+        Motion {
+            mask,
+            ..Default::default()
+        }
+        .masked()
+    }
+
+    #[test]
+    fn masks_regions() {
+        assert!(masking(vec![]).iter().all(|masked| !masked));
+        assert!(
+            masking(vec![[0.0, 0.0, 1.0, 1.0]])
+                .iter()
+                .all(|masked| *masked)
+        );
+
+        // The top tenth, rounded out to whole pixels
+        let masked = masking(vec![[0.0, 0.0, 1.0, 0.1]]);
+        let rows = (0.1 * HEIGHT as f64).ceil() as usize;
+        assert!(masked[..rows * WIDTH].iter().all(|masked| *masked));
+        assert!(masked[rows * WIDTH..].iter().all(|masked| !masked));
+
+        // The right half, clipped to the picture
+        let masked = masking(vec![[0.5, 0.0, 0.75, 1.0]]);
+        for row in masked.chunks_exact(WIDTH) {
+            assert_eq!(row.iter().filter(|masked| **masked).count(), WIDTH / 2);
+            assert!(row[WIDTH / 2..].iter().all(|masked| *masked));
+        }
+    }
+
+    #[test]
+    fn counts_pixels_over_the_threshold() {
+        let previous = vec![100; WIDTH * HEIGHT];
+        let mut frame = previous.clone();
+        let masked = vec![false; WIDTH * HEIGHT];
+        assert_eq!(Motion::level(&frame, &previous, &masked, 25), 0.0);
+
+        // A quarter of the picture changes by exactly the threshold, which
+        // doesn't count, then by more
+        let quarter = WIDTH * HEIGHT / 4;
+        frame[..quarter].fill(125);
+        assert_eq!(Motion::level(&frame, &previous, &masked, 25), 0.0);
+        frame[..quarter].fill(74);
+        assert_eq!(Motion::level(&frame, &previous, &masked, 25), 0.25);
+    }
+
+    #[test]
+    fn ignores_masked_pixels() {
+        let previous = vec![100; WIDTH * HEIGHT];
+        let mut frame = previous.clone();
+        let mut masked = vec![false; WIDTH * HEIGHT];
+        // Half masked, with every masked pixel and half of the unmasked ones changing
+        let half = WIDTH * HEIGHT / 2;
+        masked[..half].fill(true);
+        frame[..half + half / 2].fill(200);
+        assert_eq!(Motion::level(&frame, &previous, &masked, 25), 0.5);
+    }
+}
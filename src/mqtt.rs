@@ -0,0 +1,314 @@
+//! Publishing source health and events to an MQTT broker, and taking commands
+//! from it.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use rumqttc::{Client, Event, LastWill, MqttOptions, Packet, QoS};
+use serde::Deserialize;
+use serde_json::json;
+use tracing::{error, info, warn};
+
+use crate::{
+    SourceEvent,
+    control::{Command, Commands},
+};
+
+/// `[mqtt]`
+#[derive(Debug, Clone, Deserialize)]
+pub struct MqttConfig {
+    host: String,
+    #[serde(default = "default_port")]
+    port: u16,
+    #[serde(default = "default_client_id")]
+    client_id: String,
+    username: Option<String>,
+    password: Option<String>,
+    /// Prefix for every topic
+    #[serde(default = "default_topic")]
+    topic: String,
+}
+
+fn default_port() -> u16 {
+    1883
+}
+
+fn default_client_id() -> String {
+    "pi-frame".to_string()
+}
+
+fn default_topic() -> String {
+    "pi-frame".to_string()
+}
+
+/// Seconds between reconnection attempts
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// A connection to the broker, maintained by a background thread. Cheap to clone.
+#[derive(Clone)]
+pub struct Mqtt {
+    client: Client,
+    topic: String,
+    /// The latest retained message for each topic, sent again on reconnecting
+    retained: Arc<Mutex<HashMap<String, String>>>,
+}
+
+impl Mqtt {
+    pub fn start(config: &MqttConfig, commands: Commands) -> Self {
+        let topic = config.topic.trim_end_matches('/').to_string();
+        let status = format!("{topic}/status");
+        let mut options = MqttOptions::new(&config.client_id, &config.host, config.port);
+        options.set_keep_alive(Duration::from_secs(30));
+        options.set_last_will(LastWill::new(&status, "offline", QoS::AtLeastOnce, true));
+        if let Some(username) = &config.username {
+            options.set_credentials(username, config.password.clone().unwrap_or_default());
+        }
+
+        let (client, mut connection) = Client::new(options, 64);
+        let mqtt = Self {
+            client,
+            topic,
+            retained: Default::default(),
+        };
+        mqtt.publish_retained(&status, "online");
+
+        let thread_mqtt = mqtt.clone();
+        std::thread::spawn(move || {
+            let mqtt = thread_mqtt;
+            let commands_topic = format!("{}/command/", mqtt.topic);
+            for notification in connection.iter() {
+                match notification {
+                    Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                        info!("Connected to MQTT broker");
+                        mqtt.resubscribe(&commands_topic);
+                    }
+                    Ok(Event::Incoming(Packet::Publish(publish))) => {
+                        let Some(command) = publish.topic.strip_prefix(&commands_topic) else {
+                            continue;
+                        };
+                        let payload = String::from_utf8_lossy(&publish.payload);
+                        match parse_command(command, payload.trim()) {
+                            Ok(command) => {
+                                if !commands.send(command) {
+                                    break;
+                                }
+                            }
+                            Err(e) => warn!("Bad MQTT command on {}: {e}", publish.topic),
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(e) => {
+                        warn!("MQTT connection error, retrying: {e}");
+                        std::thread::sleep(RECONNECT_DELAY);
+                    }
+                }
+            }
+        });
+
+        mqtt
+    }
+
+    /// Subscribe to commands and send the retained state again, after
+    /// (re)connecting.
+    fn resubscribe(&self, commands_topic: &str) {
+        if let Err(e) = self
+            .client
+            .try_subscribe(format!("{commands_topic}+"), QoS::AtLeastOnce)
+        {
+            error!("Failed to subscribe to MQTT commands: {e}");
+        }
+        let retained = self.retained.lock().unwrap().clone();
+        for (topic, payload) in retained {
+            self.publish(&topic, true, payload);
+        }
+    }
+
+    fn publish(&self, topic: &str, retain: bool, payload: String) {
+        if let Err(e) = self
+            .client
+            .try_publish(topic, QoS::AtLeastOnce, retain, payload)
+        {
+            warn!("Failed to publish to MQTT topic {topic}: {e}");
+        }
+    }
+
+    fn publish_retained(&self, topic: &str, payload: &str) {
+        let previous = self
+            .retained
+            .lock()
+            .unwrap()
+            .insert(topic.to_string(), payload.to_string());
+        if previous.as_deref() != Some(payload) {
+            self.publish(topic, true, payload.to_string());
+        }
+    }
+
+    /// Publish an event from one of the sources.
+    pub fn publish_event(&self, event: &SourceEvent) {
+        let topic = &self.topic;
+        match event {
+            SourceEvent::State {
+                source,
+                on_fallback,
+            } => {
+                let state = if *on_fallback { "offline" } else { "ok" };
+                self.publish_retained(&format!("{topic}/sources/{source}/state"), state);
+            }
+            SourceEvent::Restarted { source, reason } => {
+                let payload = json!({ "reason": reason }).to_string();
+                self.publish(&format!("{topic}/sources/{source}/restart"), false, payload);
+            }
+            SourceEvent::Motion { source, level, .. } => {
+                let payload = json!({ "level": level }).to_string();
+                self.publish(&format!("{topic}/sources/{source}/motion"), false, payload);
+            }
+        }
+    }
+}
+
+/// Commands are published to `<topic>/command/<command>`.
+fn parse_command(command: &str, payload: &str) -> Result<Command, String> {
+    match command {
+        "focus" if payload.is_empty() => Ok(Command::Focus(None)),
+        "focus" => Ok(Command::Focus(Some(payload.to_string()))),
+        "layout" => Ok(Command::Layout(payload.to_string())),
+        "display" => match payload {
+            "on" => Ok(Command::Display(Some(true))),
+            "off" => Ok(Command::Display(Some(false))),
+            "auto" => Ok(Command::Display(None)),
+            _ => Err(format!("expected on, off or auto, not {payload:?}")),
+        },
+        "volume" => payload
+            .parse()
+            .map(Command::Volume)
+            .map_err(|_| format!("expected a volume in percent, not {payload:?}")),
+        "mute" => Ok(Command::Mute(true)),
+        "unmute" => Ok(Command::Mute(false)),
+        "record" => {
+            let mut parts = payload.split_whitespace();
+            let source = parts
+                .next()
+                .ok_or("expected a source, and optionally seconds")?;
+            Ok(Command::Record {
+                source: source.to_string(),
+                seconds: parse_seconds(parts.next())?,
+            })
+        }
+        "talk" => {
+            let mut parts = payload.split_whitespace();
+            Ok(Command::Talk {
+                source: parts.next().map(str::to_string),
+                seconds: parse_seconds(parts.next())?,
+            })
+        }
+        "hangup" => Ok(Command::StopTalking),
+        "reload" => Ok(Command::Reload),
+        _ => Err(format!("unknown command {command:?}")),
+    }
+}
+
+fn parse_seconds(seconds: Option<&str>) -> Result<Option<u32>, String> {
+    seconds
+        .map(|seconds| {
+            seconds
+                .parse()
+                .map_err(|_| format!("expected a number of seconds, not {seconds:?}"))
+        })
+        .transpose()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_focus_and_layout() {
+        assert!(matches!(
+            parse_command("focus", "door"),
+            Ok(Command::Focus(Some(source))) if source == "door"
+        ));
+        assert!(matches!(
+            parse_command("focus", ""),
+            Ok(Command::Focus(None))
+        ));
+        assert!(matches!(
+            parse_command("layout", "2x2"),
+            Ok(Command::Layout(layout)) if layout == "2x2"
+        ));
+    }
+
+    #[test]
+    fn parses_display() {
+        assert!(matches!(
+            parse_command("display", "on"),
+            Ok(Command::Display(Some(true)))
+        ));
+        assert!(matches!(
+            parse_command("display", "off"),
+            Ok(Command::Display(Some(false)))
+        ));
+        assert!(matches!(
+            parse_command("display", "auto"),
+            Ok(Command::Display(None))
+        ));
+        assert!(parse_command("display", "dim").is_err());
+    }
+
+    #[test]
+    fn parses_audio() {
+        assert!(matches!(
+            parse_command("volume", "40"),
+            Ok(Command::Volume(40))
+        ));
+        assert!(parse_command("volume", "loud").is_err());
+        assert!(parse_command("volume", "-1").is_err());
+        assert!(matches!(parse_command("mute", ""), Ok(Command::Mute(true))));
+        assert!(matches!(
+            parse_command("unmute", ""),
+            Ok(Command::Mute(false))
+        ));
+    }
+
+    #[test]
+    fn parses_record() {
+        assert!(matches!(
+            parse_command("record", "door 30"),
+            Ok(Command::Record { source, seconds: Some(30) }) if source == "door"
+        ));
+        assert!(matches!(
+            parse_command("record", "door"),
+            Ok(Command::Record { source, seconds: None }) if source == "door"
+        ));
+        assert!(parse_command("record", "").is_err());
+        assert!(parse_command("record", "door soon").is_err());
+    }
+
+    #[test]
+    fn parses_talk() {
+        assert!(matches!(
+            parse_command("talk", "door 10"),
+            Ok(Command::Talk { source: Some(source), seconds: Some(10) }) if source == "door"
+        ));
+        assert!(matches!(
+            parse_command("talk", ""),
+            Ok(Command::Talk {
+                source: None,
+                seconds: None
+            })
+        ));
+        assert!(parse_command("talk", "door ages").is_err());
+        assert!(matches!(
+            parse_command("hangup", ""),
+            Ok(Command::StopTalking)
+        ));
+    }
+
+    #[test]
+    fn rejects_unknown_commands() {
+        assert!(matches!(parse_command("reload", ""), Ok(Command::Reload)));
+        assert!(parse_command("restart", "door").is_err());
+    }
+}
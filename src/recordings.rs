@@ -0,0 +1,287 @@
+//! Recording clips of RTSP sources to files, from the H.264 they send, without
+//! re-encoding it.
+
+use std::{path::PathBuf, time::Duration};
+
+use gstreamer::prelude::*;
+use serde::Deserialize;
+use tracing::{error, info, warn};
+
+use crate::RTSP_PREFIX;
+
+/// `[recordings]`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RecordingConfig {
+    directory: PathBuf,
+    #[serde(default)]
+    format: RecordingFormat,
+    /// Seconds of video from before a recording is started to include in it
+    #[serde(default = "default_pre_roll")]
+    pub pre_roll: u32,
+    /// Seconds to record for, when a recording is started without a length
+    #[serde(default = "default_length")]
+    length: u32,
+    /// The longest recording that can be asked for, in seconds
+    #[serde(default = "default_max_length")]
+    max_length: u32,
+}
+
+fn default_pre_roll() -> u32 {
+    5
+}
+
+fn default_length() -> u32 {
+    30
+}
+
+fn default_max_length() -> u32 {
+    600
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RecordingFormat {
+    #[default]
+    Mp4,
+    #[serde(alias = "matroska")]
+    Mkv,
+}
+
+impl RecordingFormat {
+    fn extension(&self) -> &'static str {
+        match self {
+            RecordingFormat::Mp4 => "mp4",
+            RecordingFormat::Mkv => "mkv",
+        }
+    }
+
+    fn muxer(&self) -> &'static str {
+        match self {
+            RecordingFormat::Mp4 => "mp4mux",
+            RecordingFormat::Mkv => "matroskamux",
+        }
+    }
+}
+
+/// The pipeline description for a branch fed from the tee named `tee`, which
+/// holds the source's H.264 back by `pre_roll` seconds, ending back at the tee
+/// so the main branch can carry on from it. While nothing's being recorded the
+/// valve is closed, and parked on a fakesink.
+pub fn branch_description(tee: &str, id: &str, pre_roll: u32) -> String {
+    let pre_roll = gstreamer::ClockTime::from_seconds(pre_roll.into());
+    let max_size = pre_roll + gstreamer::ClockTime::from_seconds(2);
+    format!(
+        r#"! tee name={tee:?}
+            {tee}. ! queue min-threshold-time={pre_roll} max-size-time={max_size}
+                max-size-buffers=0 max-size-bytes=0 leaky=downstream
+            ! valve name="{id}_record_valve" drop=true
+            ! fakesink name="{id}_record_parked" sync=false async=false
+            {tee}. "#,
+        pre_roll = pre_roll.nseconds(),
+        max_size = max_size.nseconds(),
+    )
+}
+
+/// Starts recordings from a source's recording branch.
+pub struct Recorder {
+    source: String,
+    valve: gstreamer::Element,
+    parked: gstreamer::Element,
+}
+
+impl Recorder {
+    /// The recorder for an RTSP source, if it's in the pipeline.
+    pub fn find(pipeline: &gstreamer::Pipeline, source: &str) -> Option<Self> {
+        let id = format!("{RTSP_PREFIX}{source}");
+        let valve = pipeline.by_name(&format!("{id}_record_valve"))?;
+        let parked = pipeline.by_name(&format!("{id}_record_parked"))?;
+        Some(Self {
+            source: source.to_string(),
+            valve,
+            parked,
+        })
+    }
+
+    /// Record for `seconds`, or the configured length, from `pre_roll` seconds
+    /// ago. The clip is finished on the main loop, which this must be called
+    /// from. Returns the path it's being written to.
+    pub fn start(&self, config: &RecordingConfig, seconds: Option<u32>) -> Result<PathBuf, String> {
+        let seconds = seconds.unwrap_or(config.length);
+        if seconds == 0 || seconds > config.max_length {
+            return Err(format!(
+                "recordings must be 1 to {} seconds long",
+                config.max_length
+            ));
+        }
+        let valve_src = self.valve.static_pad("src").ok_or("valve has no src pad")?;
+        let parked_sink = self
+            .parked
+            .static_pad("sink")
+            .ok_or("fakesink has no sink pad")?;
+        if !parked_sink.is_linked() {
+            return Err(format!("{:?} is already being recorded", self.source));
+        }
+        let bin = self
+            .valve
+            .parent()
+            .and_then(|parent| parent.downcast::<gstreamer::Bin>().ok())
+            .ok_or("valve isn't in a bin")?;
+
+        std::fs::create_dir_all(&config.directory)
+            .map_err(|e| format!("can't create {}: {e}", config.directory.display()))?;
+        let now = glib::DateTime::now_local()
+            .and_then(|now| now.format("%Y%m%d-%H%M%S"))
+            .map_err(|e| e.to_string())?;
+        let path = config.directory.join(format!(
+            "{}-{now}.{}",
+            self.source,
+            config.format.extension()
+        ));
+
+        // The tee's H.264 is in whatever form the decoder wanted, so parse it
+        // again into the form the muxer wants
+        let clip = gstreamer::parse::bin_from_description(
+            &format!(
+                "h264parse ! {} ! filesink name=file sync=false async=false",
+                config.format.muxer()
+            ),
+            true,
+        )
+        .map_err(|e| e.to_string())?;
+        let file = clip.by_name("file").ok_or("no filesink")?;
+        file.set_property("location", path.to_string_lossy().to_string());
+        let clip_sink = clip.static_pad("sink").ok_or("clip has no sink pad")?;
+
+        bin.add(&clip).map_err(|e| e.to_string())?;
+        let linked = clip
+            .sync_state_with_parent()
+            .map_err(|e| e.to_string())
+            .and_then(|()| valve_src.unlink(&parked_sink).map_err(|e| e.to_string()))
+            .and_then(|()| valve_src.link(&clip_sink).map_err(|e| e.to_string()));
+        if let Err(e) = linked {
+            let _ = clip.set_state(gstreamer::State::Null);
+            let _ = bin.remove(&clip);
+            if !parked_sink.is_linked() {
+                let _ = valve_src.link(&parked_sink);
+            }
+            return Err(e);
+        }
+
+        // Clips have to start from a keyframe to be playable
+        valve_src.add_probe(gstreamer::PadProbeType::BUFFER, |_pad, info| {
+            match info.buffer() {
+                Some(buffer) if buffer.flags().contains(gstreamer::BufferFlags::DELTA_UNIT) => {
+                    gstreamer::PadProbeReturn::Drop
+                }
+                _ => gstreamer::PadProbeReturn::Remove,
+            }
+        });
+        self.valve.set_property("drop", false);
+        info!(
+            "Recording {} for {seconds}s to {}",
+            self.source,
+            path.display()
+        );
+
+        // What comes out of the valve is `pre_roll` seconds behind
+        let clip = Clip {
+            source: self.source.clone(),
+            path: path.clone(),
+            valve: self.valve.clone(),
+            parked: self.parked.clone(),
+            bin: clip,
+        };
+        let length = Duration::from_secs(u64::from(seconds) + u64::from(config.pre_roll));
+        glib::timeout_add_local_once(length, move || clip.finish());
+        Ok(path)
+    }
+}
+
+/// A recording in progress
+struct Clip {
+    source: String,
+    path: PathBuf,
+    valve: gstreamer::Element,
+    parked: gstreamer::Element,
+    bin: gstreamer::Bin,
+}
+
+impl Clip {
+    /// Stop feeding the clip, and once the muxer's written everything out,
+    /// take it out of the pipeline.
+    fn finish(self) {
+        // The source has been restarted or the pipeline rebuilt, taking the
+        // clip with it
+        if self.bin.current_state() == gstreamer::State::Null {
+            warn!(
+                "Recording of {} was cut short, {} may not be playable",
+                self.source,
+                self.path.display()
+            );
+            return;
+        }
+        // The valve ignores the muxer refusing anything after EOS once it's closed
+        self.valve.set_property("drop", true);
+
+        let Some(file) = self
+            .bin
+            .by_name("file")
+            .and_then(|file| file.static_pad("sink"))
+        else {
+            return;
+        };
+        let Some(clip_sink) = self.bin.static_pad("sink") else {
+            return;
+        };
+        let clip = std::sync::Mutex::new(Some(self));
+        file.add_probe(
+            gstreamer::PadProbeType::EVENT_DOWNSTREAM,
+            move |_pad, info| {
+                let is_eos = info
+                    .event()
+                    .is_some_and(|event| event.type_() == gstreamer::EventType::Eos);
+                if !is_eos {
+                    return gstreamer::PadProbeReturn::Ok;
+                }
+                // Elements can't be removed from their own streaming thread
+                if let Some(clip) = clip.lock().unwrap().take() {
+                    glib::idle_add_once(move || clip.remove());
+                }
+                gstreamer::PadProbeReturn::Remove
+            },
+        );
+        clip_sink.send_event(gstreamer::event::Eos::new());
+    }
+
+    fn remove(self) {
+        let result = (|| -> Result<(), Box<dyn std::error::Error>> {
+            let valve_src = self.valve.static_pad("src").ok_or("valve has no src pad")?;
+            let parked_sink = self.parked.static_pad("sink").ok_or("no sink pad")?;
+            let clip_sink = self.bin.static_pad("sink").ok_or("clip has no sink pad")?;
+            valve_src.unlink(&clip_sink)?;
+            valve_src.link(&parked_sink)?;
+            self.bin.set_state(gstreamer::State::Null)?;
+            if let Some(parent) = self
+                .bin
+                .parent()
+                .and_then(|parent| parent.downcast::<gstreamer::Bin>().ok())
+            {
+                parent.remove(&self.bin)?;
+            }
+            Ok(())
+        })();
+        match result {
+            Ok(()) => info!(
+                "Saved recording of {} to {}",
+                self.source,
+                self.path.display()
+            ),
+            Err(e) => error!(
+                "Failed to finish recording of {} to {}: {e}",
+                self.source,
+                self.path.display()
+            ),
+        }
+    }
+}
@@ -0,0 +1,243 @@
+//! Streaming the composited display over the network, so the same wall can be
+//! watched elsewhere, from an embedded RTSP server or as HLS segments.
+
+use std::{path::PathBuf, sync::Mutex};
+
+use gstreamer::prelude::*;
+use gstreamer_app::{AppSink, AppSinkCallbacks, AppSrc};
+use gstreamer_rtsp_server::prelude::*;
+use serde::Deserialize;
+use tracing::{debug, error, info};
+
+/// The appsink at the end of the branch, feeding the RTSP server
+const RTSP_SINK: &str = "restream_rtsp";
+/// What the branch sends to each output
+const STREAM_CAPS: &str = "video/x-h264,stream-format=byte-stream,alignment=au";
+
+/// The RTSP server outlives the pipeline, which is rebuilt from scratch after
+/// failures, so each pipeline's appsink finds the server's streams here.
+static STREAMS: Mutex<Vec<AppSrc>> = Mutex::new(Vec::new());
+
+/// `[restream]`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RestreamConfig {
+    #[serde(default)]
+    encoder: Encoder,
+    /// kbit/s
+    #[serde(default = "default_bitrate")]
+    bitrate: u32,
+    /// Seconds between keyframes, which is how long new viewers may wait for a
+    /// picture
+    #[serde(default = "default_keyframe_interval")]
+    keyframe_interval: u32,
+    pub rtsp: Option<RtspOutput>,
+    hls: Option<HlsOutput>,
+}
+
+fn default_bitrate() -> u32 {
+    4000
+}
+
+fn default_keyframe_interval() -> u32 {
+    2
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoder {
+    /// The Raspberry Pi's hardware encoder
+    #[default]
+    V4l2h264enc,
+    X264enc,
+}
+
+/// `[restream.rtsp]`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RtspOutput {
+    port: u16,
+    path: String,
+}
+
+impl Default for RtspOutput {
+    fn default() -> Self {
+        Self {
+            port: 8554,
+            path: "/mosaic".to_string(),
+        }
+    }
+}
+
+/// `[restream.hls]`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HlsOutput {
+    directory: PathBuf,
+    /// Seconds per segment
+    #[serde(default = "default_segment_duration")]
+    segment_duration: u32,
+    /// Segments listed in the playlist
+    #[serde(default = "default_playlist_length")]
+    playlist_length: u32,
+}
+
+fn default_segment_duration() -> u32 {
+    2
+}
+
+fn default_playlist_length() -> u32 {
+    5
+}
+
+impl RestreamConfig {
+    /// Create the HLS directory, so a bad one is found when the config is
+    /// loaded rather than each time the pipeline is built.
+    pub fn prepare(&self) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(hls) = &self.hls {
+            std::fs::create_dir_all(&hls.directory)
+                .map_err(|e| format!("can't create {}: {e}", hls.directory.display()))?;
+        }
+        Ok(())
+    }
+
+    /// The pipeline description for a branch fed from the tee named `tee`, which
+    /// encodes the display at `framerate` and sends it to each output, ending
+    /// back at the tee so the framebuffer can carry on from it.
+    pub fn branch_description(
+        &self,
+        tee: &str,
+        framerate: u32,
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        let keyframe_interval = self.keyframe_interval.max(1) * framerate;
+        let encoder = match self.encoder {
+            Encoder::V4l2h264enc => format!(
+                r#"v4l2h264enc extra-controls="controls,video_bitrate={},h264_i_frame_period={keyframe_interval}"
+                    ! video/x-h264,level=(string)4"#,
+                self.bitrate * 1000
+            ),
+            Encoder::X264enc => format!(
+                "x264enc tune=zerolatency speed-preset=ultrafast bitrate={} key-int-max={keyframe_interval}",
+                self.bitrate
+            ),
+        };
+
+        let mut outputs = vec![];
+        // Dropping encoded frames would corrupt the picture until the next
+        // keyframe, so frames are only dropped before the encoder, and by each
+        // viewer's appsrc
+        if self.rtsp.is_some() {
+            outputs.push(format!(
+                "queue ! appsink name={RTSP_SINK:?} sync=false async=false"
+            ));
+        }
+        if let Some(hls) = &self.hls {
+            let segments = hls.directory.join("segment%05d.ts");
+            let playlist = hls.directory.join("playlist.m3u8");
+            outputs.push(format!(
+                "queue ! hlssink2 location={segments:?} playlist-location={playlist:?}
+                    target-duration={} playlist-length={} max-files={}",
+                hls.segment_duration,
+                hls.playlist_length,
+                hls.playlist_length * 2
+            ));
+        }
+        let outputs = match outputs.as_slice() {
+            [] => return Err("[restream] needs an rtsp or hls output".into()),
+            [output] => format!("! {output}"),
+            outputs => {
+                let branches = outputs
+                    .iter()
+                    .map(|output| format!("restream_outputs. ! {output}"))
+                    .collect::<Vec<_>>();
+                format!("! tee name=restream_outputs {}", branches.join(" "))
+            }
+        };
+
+        Ok(format!(
+            r#"! tee name={tee:?}
+                {tee}. ! queue leaky=downstream max-size-buffers=2
+                ! videoconvert ! video/x-raw,format=I420
+                ! {encoder}
+                ! h264parse config-interval=-1 ! {STREAM_CAPS}
+                {outputs}
+                {tee}. "#
+        ))
+    }
+}
+
+/// Serve whatever the pipeline's RTSP branch encodes at
+/// `rtsp://<host>:<port><path>`, for as long as the main loop runs.
+pub fn start_server(rtsp: &RtspOutput) -> Result<(), Box<dyn std::error::Error>> {
+    let server = gstreamer_rtsp_server::RTSPServer::new();
+    server.set_service(&rtsp.port.to_string());
+
+    // Shared, so every viewer gets the same stream, which the server stops once
+    // the last one leaves
+    let factory = gstreamer_rtsp_server::RTSPMediaFactory::new();
+    factory.set_launch(&format!(
+        r#"( appsrc name=src is-live=true format=time do-timestamp=true caps="{STREAM_CAPS}"
+            max-buffers=100 leaky-type=downstream
+            ! h264parse ! rtph264pay name=pay0 pt=96 config-interval=-1 )"#
+    ));
+    factory.set_shared(true);
+    factory.connect_media_configure(|_factory, media| {
+        let appsrc = media
+            .element()
+            .downcast::<gstreamer::Bin>()
+            .ok()
+            .and_then(|bin| bin.by_name("src"))
+            .and_then(|src| src.downcast::<AppSrc>().ok());
+        match appsrc {
+            Some(appsrc) => {
+                debug!("Starting RTSP stream");
+                STREAMS.lock().unwrap().push(appsrc);
+            }
+            None => error!("RTSP stream has no appsrc"),
+        }
+    });
+    server
+        .mount_points()
+        .ok_or("RTSP server has no mount points")?
+        .add_factory(&rtsp.path, factory);
+
+    server.attach(None)?;
+    info!(
+        "Serving the display at rtsp://0.0.0.0:{}{}",
+        rtsp.port, rtsp.path
+    );
+    Ok(())
+}
+
+/// Send the video encoded by `bin`'s RTSP branch, if it has one, to the
+/// server's streams.
+pub fn feed(bin: &gstreamer::Bin) {
+    let Some(sink) = bin
+        .by_name(RTSP_SINK)
+        .and_then(|sink| sink.downcast::<AppSink>().ok())
+    else {
+        return;
+    };
+    let callbacks = AppSinkCallbacks::builder()
+        .new_sample(|sink| {
+            let sample = sink.pull_sample().map_err(|_| gstreamer::FlowError::Eos)?;
+            let mut streams = STREAMS.lock().unwrap();
+            if streams.is_empty() {
+                return Ok(gstreamer::FlowSuccess::Ok);
+            }
+            let Some(mut buffer) = sample.buffer_owned() else {
+                return Ok(gstreamer::FlowSuccess::Ok);
+            };
+            // Each stream has its own clock, and timestamps buffers as they arrive
+            {
+                let buffer = buffer.make_mut();
+                buffer.set_pts(None);
+                buffer.set_dts(None);
+            }
+            // Streams that have stopped refuse buffers, and are forgotten
+            streams.retain(|appsrc| appsrc.push_buffer(buffer.clone()).is_ok());
+            Ok(gstreamer::FlowSuccess::Ok)
+        })
+        .build();
+    sink.set_callbacks(callbacks);
+}
@@ -0,0 +1,401 @@
+//! Turning the display off, or dimming it, at scheduled times.
+
+use std::{
+    cell::RefCell,
+    os::fd::AsRawFd,
+    path::{Path, PathBuf},
+    rc::{Rc, Weak},
+    time::{Duration, Instant},
+};
+
+use serde::Deserialize;
+use tracing::{error, info};
+
+use crate::supervisor::SupervisorHandle;
+
+/// `[schedule]`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ScheduleConfig {
+    /// A sysfs backlight `brightness` file. Without one, the framebuffer is
+    /// blanked instead, and dimming isn't possible.
+    backlight: Option<PathBuf>,
+    /// The backlight brightness outside of any period, by default whatever it
+    /// was when pi-frame started
+    brightness: Option<u32>,
+    /// Stop the pipeline while the display is off, disconnecting from the cameras
+    pause_sources: bool,
+    /// Seconds to turn the display back on for when woken, eg: by motion
+    wake_for: u64,
+    periods: Vec<Period>,
+}
+
+impl Default for ScheduleConfig {
+    fn default() -> Self {
+        Self {
+            backlight: None,
+            brightness: None,
+            pause_sources: false,
+            wake_for: 60,
+            periods: vec![],
+        }
+    }
+}
+
+/// eg: `{ from = "23:00", to = "06:30", days = ["weekdays"] }`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Period {
+    from: TimeOfDay,
+    /// May be earlier than `from`, for periods running past midnight
+    to: TimeOfDay,
+    /// The days the period starts on, by default every day
+    #[serde(default)]
+    days: Vec<Day>,
+    /// 0 turns the display off
+    #[serde(default)]
+    brightness: u32,
+}
+
+/// Minutes since midnight
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(try_from = "String")]
+struct TimeOfDay(u32);
+
+impl TryFrom<String> for TimeOfDay {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        let invalid = || format!("invalid time {s:?}, expected HH:MM");
+        let (hours, minutes) = s.split_once(':').ok_or_else(invalid)?;
+        let hours = hours.parse::<u32>().map_err(|_| invalid())?;
+        let minutes = minutes.parse::<u32>().map_err(|_| invalid())?;
+        if hours > 23 || minutes > 59 {
+            return Err(invalid());
+        }
+        Ok(Self(hours * 60 + minutes))
+    }
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Day {
+    Mon,
+    Tue,
+    Wed,
+    Thu,
+    Fri,
+    Sat,
+    Sun,
+    Weekdays,
+    Weekends,
+}
+
+impl Day {
+    /// Whether this includes the given ISO 8601 day of the week, 1 = Monday
+    fn contains(&self, day_of_week: u32) -> bool {
+        match self {
+            Day::Weekdays => day_of_week <= 5,
+            Day::Weekends => day_of_week >= 6,
+            day => *day as u32 + 1 == day_of_week,
+        }
+    }
+}
+
+impl Period {
+    fn starts_on(&self, day_of_week: u32) -> bool {
+        self.days.is_empty() || self.days.iter().any(|day| day.contains(day_of_week))
+    }
+
+    fn contains(&self, day_of_week: u32, time: TimeOfDay) -> bool {
+        if self.from <= self.to {
+            return self.starts_on(day_of_week) && time >= self.from && time < self.to;
+        }
+        // Past midnight, the period belongs to the day it started on
+        let yesterday = if day_of_week == 1 { 7 } else { day_of_week - 1 };
+        (self.starts_on(day_of_week) && time >= self.from)
+            || (self.starts_on(yesterday) && time < self.to)
+    }
+}
+
+impl ScheduleConfig {
+    /// Whether the pipeline is stopped while the display is off.
+    pub fn pauses_sources(&self) -> bool {
+        self.pause_sources
+    }
+
+    /// The brightness the schedule calls for right now.
+    fn brightness(&self, on: u32) -> u32 {
+        let Ok(now) = glib::DateTime::now_local() else {
+            return on;
+        };
+        let day_of_week = now.day_of_week() as u32;
+        let time = TimeOfDay((now.hour() * 60 + now.minute()) as u32);
+        self.periods
+            .iter()
+            .find(|period| period.contains(day_of_week, time))
+            .map_or(on, |period| period.brightness)
+    }
+}
+
+/// From linux/fb.h
+const FBIOBLANK: libc::c_ulong = 0x4611;
+const FB_BLANK_UNBLANK: libc::c_int = 0;
+const FB_BLANK_POWERDOWN: libc::c_int = 4;
+
+fn blank_framebuffer(framebuffer: &Path, blank: bool) -> std::io::Result<()> {
+    let device = std::fs::File::open(framebuffer)?;
+    let mode = if blank {
+        FB_BLANK_POWERDOWN
+    } else {
+        FB_BLANK_UNBLANK
+    };
+    // SAFETY: FBIOBLANK takes its argument by value
+    if unsafe { libc::ioctl(device.as_raw_fd(), FBIOBLANK as _, mode) } < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Applies the schedule from the main loop, for as long as it exists. The
+/// display can also be turned on or off by hand, overriding the schedule.
+pub struct Scheduler {
+    state: Rc<RefCell<State>>,
+    timer: Option<glib::SourceId>,
+}
+
+struct State {
+    config: ScheduleConfig,
+    framebuffer: PathBuf,
+    supervisor: SupervisorHandle,
+    /// The brightness outside of any period
+    on: u32,
+    current: Option<u32>,
+    /// Set when woken, to override the schedule for a while
+    awake_until: Option<Instant>,
+    /// Set when the display has been turned on or off by hand
+    forced: Option<bool>,
+}
+
+impl State {
+    fn update(&mut self) {
+        let awake = self.awake_until.is_some_and(|until| Instant::now() < until);
+        let brightness = match self.forced {
+            Some(true) => self.on,
+            Some(false) => 0,
+            None if awake => self.on,
+            None => self.config.brightness(self.on),
+        };
+        self.apply(brightness);
+    }
+
+    fn apply(&mut self, brightness: u32) {
+        if self.current == Some(brightness) {
+            return;
+        }
+        info!("Setting display brightness to {brightness}");
+
+        let result = match &self.config.backlight {
+            Some(backlight) => std::fs::write(backlight, brightness.to_string()),
+            None => blank_framebuffer(&self.framebuffer, brightness == 0),
+        };
+        if let Err(e) = result {
+            error!("Failed to set display brightness: {e:?}");
+        }
+
+        if self.config.pause_sources {
+            if brightness == 0 {
+                self.supervisor.pause();
+            } else if self.current == Some(0) {
+                self.supervisor.resume();
+            }
+        }
+        self.current = Some(brightness);
+    }
+}
+
+impl Scheduler {
+    pub fn start(
+        config: ScheduleConfig,
+        framebuffer: PathBuf,
+        supervisor: SupervisorHandle,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let on = match (config.brightness, &config.backlight) {
+            (Some(brightness), _) => brightness,
+            (None, Some(backlight)) => std::fs::read_to_string(backlight)
+                .map_err(|e| format!("can't read {}: {e}", backlight.display()))?
+                .trim()
+                .parse()?,
+            (None, None) => 1,
+        };
+        let state = Rc::new(RefCell::new(State {
+            config,
+            framebuffer,
+            supervisor,
+            on,
+            // The display is assumed to start out on
+            current: Some(on),
+            awake_until: None,
+            forced: None,
+        }));
+
+        let update = {
+            let state = Rc::downgrade(&state);
+            move || {
+                let Some(state) = state.upgrade() else {
+                    return glib::ControlFlow::Break;
+                };
+                state.borrow_mut().update();
+                glib::ControlFlow::Continue
+            }
+        };
+        update();
+        let timer = glib::timeout_add_seconds_local(30, update);
+
+        Ok(Self {
+            state,
+            timer: Some(timer),
+        })
+    }
+
+    pub fn handle(&self) -> SchedulerHandle {
+        SchedulerHandle(Rc::downgrade(&self.state))
+    }
+}
+
+#[derive(Clone)]
+pub struct SchedulerHandle(Weak<RefCell<State>>);
+
+impl SchedulerHandle {
+    /// Turn the display on for `wake_for` seconds, regardless of the schedule.
+    pub fn wake(&self) {
+        let Some(state) = self.0.upgrade() else {
+            return;
+        };
+        let wake_for = Duration::from_secs(state.borrow().config.wake_for);
+        state.borrow_mut().awake_until = Some(Instant::now() + wake_for);
+        state.borrow_mut().update();
+
+        // Go back to the schedule promptly, rather than on the next regular update
+        let weak = self.0.clone();
+        glib::timeout_add_local_once(wake_for + Duration::from_secs(1), move || {
+            if let Some(state) = weak.upgrade() {
+                state.borrow_mut().update();
+            }
+        });
+    }
+
+    /// Turn the display on or off until told otherwise, or go back to the
+    /// schedule with `None`.
+    pub fn force(&self, on: Option<bool>) {
+        let Some(state) = self.0.upgrade() else {
+            return;
+        };
+        let mut state = state.borrow_mut();
+        state.forced = on;
+        state.update();
+    }
+}
+
+impl Drop for Scheduler {
+    /// Leave the display on for whatever is shown after pi-frame exits.
+    fn drop(&mut self) {
+        if let Some(timer) = self.timer.take() {
+            timer.remove();
+        }
+        let mut state = self.state.borrow_mut();
+        let on = state.on;
+        state.apply(on);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Parse a period as written in the config, eg: `from = "23:00", to = "06:30"`
+    fn parse(fields: &str) -> Result<Period, toml::de::Error> {
+        #[derive(Deserialize)]
+        struct Schedule {
+            period: Period,
+        }
+        toml::from_str::<Schedule>(&format!("period = {{ {fields} }}")).map(|s| s.period)
+    }
+
+    fn period(fields: &str) -> Period {
+        parse(fields).unwrap()
+    }
+
+    fn time(s: &str) -> TimeOfDay {
+        TimeOfDay::try_from(s.to_string()).unwrap()
+    }
+
+    const MON: u32 = 1;
+    const TUE: u32 = 2;
+    const FRI: u32 = 5;
+    const SAT: u32 = 6;
+    const SUN: u32 = 7;
+
+    #[test]
+    fn parses_periods() {
+        let night = period(r#"from = "23:00", to = "06:30", days = ["weekdays"], brightness = 10"#);
+        assert_eq!(night.from, TimeOfDay(23 * 60));
+        assert_eq!(night.to, TimeOfDay(6 * 60 + 30));
+        assert_eq!(night.brightness, 10);
+
+        // Every day, and off
+        let lunch = period(r#"from = "12:00", to = "13:00""#);
+        assert!(lunch.days.is_empty());
+        assert_eq!(lunch.brightness, 0);
+    }
+
+    #[test]
+    fn contains_times_within_a_day() {
+        let lunch = period(r#"from = "12:00", to = "13:00""#);
+        assert!(!lunch.contains(MON, time("11:59")));
+        assert!(lunch.contains(MON, time("12:00")));
+        assert!(lunch.contains(SUN, time("12:59")));
+        assert!(!lunch.contains(MON, time("13:00")));
+    }
+
+    #[test]
+    fn runs_past_midnight_on_the_day_it_started() {
+        let night = period(r#"from = "23:00", to = "06:30", days = ["mon"]"#);
+        assert!(night.contains(MON, time("23:30")));
+        assert!(night.contains(TUE, time("02:00")));
+        assert!(!night.contains(TUE, time("06:30")));
+        assert!(!night.contains(TUE, time("23:30")));
+        // Started on Sunday night, which isn't in the period
+        assert!(!night.contains(MON, time("02:00")));
+
+        let weeknights = period(r#"from = "23:00", to = "06:30", days = ["weekdays"]"#);
+        assert!(weeknights.contains(SAT, time("02:00")));
+        assert!(!weeknights.contains(SAT, time("23:30")));
+        assert!(!weeknights.contains(MON, time("02:00")));
+        assert!(weeknights.contains(MON, time("23:00")));
+    }
+
+    #[test]
+    fn starts_on_listed_days() {
+        let period = period(r#"from = "09:00", to = "17:00", days = ["tue", "fri", "weekends"]"#);
+        let days: Vec<_> = (MON..=SUN)
+            .filter(|&day| period.contains(day, time("12:00")))
+            .collect();
+        assert_eq!(days, [TUE, FRI, SAT, SUN]);
+    }
+
+    #[test]
+    fn rejects_invalid_periods() {
+        for invalid in [
+            r#"from = "24:00", to = "06:00""#,
+            r#"from = "23:60", to = "06:00""#,
+            r#"from = "2300", to = "06:00""#,
+            r#"from = "ab:00", to = "06:00""#,
+            r#"from = "23:00""#,
+            r#"from = "23:00", to = "06:00", days = ["someday"]"#,
+            r#"from = "23:00", to = "06:00", until = "07:00""#,
+        ] {
+            assert!(parse(invalid).is_err(), "{invalid:?} parsed");
+        }
+    }
+}
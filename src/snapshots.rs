@@ -0,0 +1,419 @@
+//! Snapshots of the display, saved at regular intervals, and of individual
+//! sources on request.
+
+use std::{
+    path::{Path, PathBuf},
+    rc::Weak,
+    sync::mpsc::{Receiver, RecvTimeoutError, Sender},
+    thread::JoinHandle,
+    time::{Duration, SystemTime},
+};
+
+use gstreamer::prelude::*;
+use gstreamer_app::AppSink;
+use image::{ImageResult, RgbImage};
+use serde::Deserialize;
+use tracing::{debug, error, info, warn};
+
+use crate::{RTSP_PREFIX, cells::Rect, fbimage, supervisor::SupervisorHandle};
+
+/// How long to wait for the framebuffer to be read
+const SNAPSHOT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// `[snapshots]`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SnapshotConfig {
+    /// Relative to the config file
+    directory: PathBuf,
+    /// Seconds between snapshots
+    #[serde(default = "default_interval")]
+    interval: u32,
+    /// The file name, without an extension or directory, with strftime-style
+    /// fields for the time the snapshot was taken
+    #[serde(default = "default_filename")]
+    filename: String,
+    #[serde(default)]
+    format: SnapshotFormat,
+    /// JPEG quality, 1-100
+    #[serde(default = "default_quality")]
+    quality: u8,
+    /// How many snapshots to keep, deleting the oldest
+    keep: Option<usize>,
+    /// Seconds to keep snapshots for
+    max_age: Option<u64>,
+    /// Also save each source's cell on its own, in a directory named after the
+    /// source
+    #[serde(default)]
+    cells: bool,
+}
+
+fn default_interval() -> u32 {
+    60
+}
+
+fn default_filename() -> String {
+    "%Y%m%d-%H%M%S".to_string()
+}
+
+fn default_quality() -> u8 {
+    85
+}
+
+impl SnapshotConfig {
+    /// Check the config, and resolve the directory against the config file's.
+    pub fn prepare(&mut self, config_dir: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        if self.interval == 0 {
+            return Err("[snapshots] interval must be at least 1 second".into());
+        }
+        self.directory = config_dir.join(&self.directory);
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SnapshotFormat {
+    Png,
+    #[default]
+    #[serde(alias = "jpg")]
+    Jpeg,
+}
+
+impl SnapshotFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            SnapshotFormat::Png => "png",
+            SnapshotFormat::Jpeg => "jpg",
+        }
+    }
+
+    pub fn encode(&self, image: &RgbImage, quality: u8) -> ImageResult<Vec<u8>> {
+        let mut buffer = vec![];
+        match self {
+            SnapshotFormat::Png => {
+                image.write_with_encoder(image::codecs::png::PngEncoder::new(&mut buffer))?
+            }
+            SnapshotFormat::Jpeg => {
+                let quality = quality.clamp(1, 100);
+                let encoder =
+                    image::codecs::jpeg::JpegEncoder::new_with_quality(&mut buffer, quality);
+                image.write_with_encoder(encoder)?
+            }
+        }
+        Ok(buffer)
+    }
+}
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    /// The snapshot thread has stopped
+    Stopped,
+    Timeout,
+    Framebuffer(String),
+}
+
+impl std::fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SnapshotError::Stopped => f.write_str("the snapshot thread has stopped"),
+            SnapshotError::Timeout => f.write_str("timed out reading the framebuffer"),
+            SnapshotError::Framebuffer(e) => f.write_str(e),
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+type SnapshotResult = Result<RgbImage, SnapshotError>;
+
+/// Reads the framebuffer on its own thread, so the main loop never waits for
+/// it. The thread stops when this is dropped.
+pub struct Snapshotter {
+    tx: Option<Sender<Sender<SnapshotResult>>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+/// A snapshot that's been asked for, but may not have been taken yet
+pub struct PendingSnapshot(Receiver<SnapshotResult>);
+
+impl Snapshotter {
+    /// Start the thread, with its own mapping of the framebuffer.
+    pub fn start(device: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let framebuffer = framebuffer::Framebuffer::new(device)
+            .map_err(|e| format!("can't open {}: {}", device.display(), e.details))?;
+        let (tx, rx) = std::sync::mpsc::channel::<Sender<SnapshotResult>>();
+        let handle = std::thread::Builder::new()
+            .name("snapshots".to_string())
+            .spawn(move || {
+                while let Ok(reply) = rx.recv() {
+                    debug!("Taking snapshot");
+                    let image = fbimage::read(&framebuffer).map_err(SnapshotError::Framebuffer);
+                    // Whoever asked may have given up waiting
+                    let _ = reply.send(image);
+                }
+            })?;
+        Ok(Self {
+            tx: Some(tx),
+            handle: Some(handle),
+        })
+    }
+
+    /// Ask for a snapshot of whatever's on the display.
+    pub fn request(&self) -> Result<PendingSnapshot, SnapshotError> {
+        let (reply, rx) = std::sync::mpsc::channel();
+        let tx = self.tx.as_ref().ok_or(SnapshotError::Stopped)?;
+        tx.send(reply).map_err(|_| SnapshotError::Stopped)?;
+        Ok(PendingSnapshot(rx))
+    }
+}
+
+impl Drop for Snapshotter {
+    /// Stop the thread, after any snapshot in progress.
+    fn drop(&mut self) {
+        self.tx.take();
+        if let Some(handle) = self.handle.take()
+            && handle.join().is_err()
+        {
+            error!("The snapshot thread panicked");
+        }
+    }
+}
+
+impl PendingSnapshot {
+    /// Wait up to `timeout` for the snapshot.
+    pub fn wait(self, timeout: Duration) -> SnapshotResult {
+        match self.0.recv_timeout(timeout) {
+            Ok(result) => result,
+            Err(RecvTimeoutError::Timeout) => Err(SnapshotError::Timeout),
+            Err(RecvTimeoutError::Disconnected) => Err(SnapshotError::Stopped),
+        }
+    }
+}
+
+/// Take a snapshot every `interval` seconds while the pipeline is running, for
+/// as long as the snapshotter and main loop run.
+pub fn start(config: SnapshotConfig, snapshotter: Weak<Snapshotter>, supervisor: SupervisorHandle) {
+    info!(
+        "Saving snapshots to {} every {}s",
+        config.directory.display(),
+        config.interval
+    );
+    glib::timeout_add_seconds_local(config.interval, move || {
+        let Some(snapshotter) = snapshotter.upgrade() else {
+            return glib::ControlFlow::Break;
+        };
+        // Nothing's being drawn while the pipeline is paused
+        let Some(cells) = supervisor.cells() else {
+            return glib::ControlFlow::Continue;
+        };
+        let cells = if config.cells {
+            cells.geometry()
+        } else {
+            vec![]
+        };
+        let now = glib::DateTime::now_local();
+        let name = match now.and_then(|now| now.format(&config.filename)) {
+            Ok(name) => name.to_string(),
+            Err(e) => {
+                error!("Failed to name snapshot with {:?}: {e}", config.filename);
+                return glib::ControlFlow::Continue;
+            }
+        };
+        let pending = match snapshotter.request() {
+            Ok(pending) => pending,
+            Err(e) => {
+                error!("Failed to take snapshot: {e}");
+                return glib::ControlFlow::Break;
+            }
+        };
+        // Encoding and writing files takes a while, so do it elsewhere too
+        let config = config.clone();
+        std::thread::spawn(move || match pending.wait(SNAPSHOT_TIMEOUT) {
+            Ok(image) => config.save(&image, &name, &cells),
+            Err(e) => error!("Failed to take snapshot: {e}"),
+        });
+        glib::ControlFlow::Continue
+    });
+}
+
+impl SnapshotConfig {
+    fn save(&self, image: &RgbImage, name: &str, cells: &[(String, Rect)]) {
+        let file_name = format!("{name}.{}", self.format.extension());
+        self.write(&self.directory, &file_name, image);
+
+        for (source, (x, y, width, height)) in cells {
+            let (x, y) = (*x.max(&0) as u32, *y.max(&0) as u32);
+            let width = (*width.max(&0) as u32).min(image.width().saturating_sub(x));
+            let height = (*height.max(&0) as u32).min(image.height().saturating_sub(y));
+            if width == 0 || height == 0 {
+                continue;
+            }
+            let cell = image::imageops::crop_imm(image, x, y, width, height).to_image();
+            self.write(&self.directory.join(source), &file_name, &cell);
+        }
+    }
+
+    fn write(&self, directory: &Path, file_name: &str, image: &RgbImage) {
+        let path = directory.join(file_name);
+        let result = self
+            .format
+            .encode(image, self.quality)
+            .map_err(|e| e.to_string())
+            .and_then(|data| {
+                if let Some(parent) = path.parent() {
+                    std::fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+                }
+                std::fs::write(&path, data).map_err(|e| e.to_string())
+            });
+        match result {
+            Ok(()) => debug!("Saved snapshot {}", path.display()),
+            Err(e) => error!("Failed to save snapshot {}: {e}", path.display()),
+        }
+        self.prune(directory);
+    }
+
+    /// Delete snapshots beyond `keep` or older than `max_age`.
+    fn prune(&self, directory: &Path) {
+        if self.keep.is_none() && self.max_age.is_none() {
+            return;
+        }
+        let entries = match std::fs::read_dir(directory) {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!("Can't list {}: {e}", directory.display());
+                return;
+            }
+        };
+        let extension = self.format.extension();
+        let mut snapshots = entries
+            .filter_map(Result::ok)
+            .filter(|entry| entry.path().extension().is_some_and(|e| e == extension))
+            .filter_map(|entry| Some((entry.metadata().ok()?.modified().ok()?, entry.path())))
+            .collect::<Vec<_>>();
+        // Newest first
+        snapshots.sort_by_key(|snapshot| std::cmp::Reverse(snapshot.0));
+
+        let now = SystemTime::now();
+        let max_age = self.max_age.map(Duration::from_secs);
+        for (index, (modified, path)) in snapshots.iter().enumerate() {
+            let too_many = self.keep.is_some_and(|keep| index >= keep);
+            let too_old = max_age.is_some_and(|max_age| {
+                now.duration_since(*modified).is_ok_and(|age| age > max_age)
+            });
+            if too_many || too_old {
+                match std::fs::remove_file(path) {
+                    Ok(()) => debug!("Removed old snapshot {}", path.display()),
+                    Err(e) => warn!("Can't remove {}: {e}", path.display()),
+                }
+            }
+        }
+    }
+}
+
+/// The pipeline description for a branch fed from the tee named `tee`, which
+/// converts the source's frames to RGB while its valve is open, ending back at
+/// the tee so the main branch can carry on from it.
+pub fn branch_description(tee: &str, id: &str) -> String {
+    format!(
+        r#"! tee name={tee:?}
+            {tee}. ! queue leaky=downstream max-size-buffers=1
+            ! valve name="{id}_snapshot_valve" drop=true
+            ! videoconvert ! video/x-raw,format=RGB
+            ! appsink name="{id}_snapshot" max-buffers=1 drop=true sync=false async=false
+            {tee}. "#
+    )
+}
+
+/// Grabs single frames from a source's snapshot branch, at the resolution
+/// they're decoded at.
+pub struct FrameGrabber {
+    valve: gstreamer::Element,
+    sink: AppSink,
+}
+
+impl FrameGrabber {
+    /// The grabber for an RTSP source, if it's in the pipeline.
+    pub fn find(pipeline: &gstreamer::Pipeline, source: &str) -> Option<Self> {
+        let id = format!("{RTSP_PREFIX}{source}");
+        let valve = pipeline.by_name(&format!("{id}_snapshot_valve"))?;
+        let sink = pipeline
+            .by_name(&format!("{id}_snapshot"))?
+            .downcast::<AppSink>()
+            .ok()?;
+        Some(Self { valve, sink })
+    }
+
+    /// Wait for the next frame. This blocks, so shouldn't be called from the
+    /// main loop.
+    pub fn grab(&self, timeout: Duration) -> Result<RgbImage, String> {
+        // Anything left over from an earlier grab is stale
+        while self
+            .sink
+            .try_pull_sample(gstreamer::ClockTime::ZERO)
+            .is_some()
+        {}
+
+        self.valve.set_property("drop", false);
+        let timeout = gstreamer::ClockTime::from_nseconds(timeout.as_nanos() as u64);
+        let sample = self.sink.try_pull_sample(timeout);
+        self.valve.set_property("drop", true);
+
+        to_image(&sample.ok_or("timed out waiting for a frame")?)
+    }
+}
+
+/// Connect to an RTSP stream just long enough to grab a frame, eg: to get one
+/// at a higher resolution than its source is being decoded at. This blocks,
+/// so shouldn't be called from the main loop.
+pub fn grab_url(url: &str, timeout: Duration) -> Result<RgbImage, String> {
+    let pipeline = gstreamer::parse::launch(&format!(
+        r#"rtspsrc location={url:?} latency=200 protocols=tcp
+            ! rtph264depay wait-for-keyframe=true ! h264parse ! v4l2h264dec
+            ! videoconvert ! video/x-raw,format=RGB
+            ! appsink name=sink max-buffers=1 drop=true sync=false"#
+    ))
+    .map_err(|e| e.to_string())?
+    .downcast::<gstreamer::Pipeline>()
+    .map_err(|_| "snapshot isn't a pipeline")?;
+    let sink = pipeline
+        .by_name("sink")
+        .and_then(|sink| sink.downcast::<AppSink>().ok())
+        .ok_or("no appsink")?;
+    let result = pipeline
+        .set_state(gstreamer::State::Playing)
+        .map_err(|e| format!("can't connect: {e}"))
+        .and_then(|_| {
+            let timeout = gstreamer::ClockTime::from_nseconds(timeout.as_nanos() as u64);
+            sink.try_pull_sample(timeout)
+                .ok_or_else(|| "timed out waiting for a frame".to_string())
+        })
+        .and_then(|sample| to_image(&sample));
+    if let Err(e) = pipeline.set_state(gstreamer::State::Null) {
+        warn!("Error stopping snapshot pipeline: {e:?}");
+    }
+    result
+}
+
+/// Copy an RGB frame out of a sample, without any padding between rows.
+fn to_image(sample: &gstreamer::Sample) -> Result<RgbImage, String> {
+    let caps = sample.caps().ok_or("frame has no caps")?;
+    let info = gstreamer_video::VideoInfo::from_caps(caps).map_err(|e| e.to_string())?;
+    let buffer = sample.buffer().ok_or("frame has no buffer")?;
+    let map = buffer.map_readable().map_err(|e| e.to_string())?;
+
+    let (width, height) = (info.width(), info.height());
+    if width == 0 || height == 0 {
+        return Err("frame is empty".to_string());
+    }
+    let stride = info.stride()[0] as usize;
+    let row = width as usize * 3;
+    if map.len() < stride * (height as usize - 1) + row {
+        return Err(format!("frame is too small for {width}x{height}"));
+    }
+    let mut pixels = Vec::with_capacity(row * height as usize);
+    for y in 0..height as usize {
+        pixels.extend_from_slice(&map[y * stride..y * stride + row]);
+    }
+    RgbImage::from_raw(width, height, pixels).ok_or_else(|| "bad frame size".to_string())
+}
@@ -0,0 +1,163 @@
+//! Offline rendering of the gzip'd raw splash images shown by the initramfs and
+//! service scripts.
+
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use flate2::{Compression, write::GzEncoder};
+use image::{DynamicImage, GrayImage, Luma, RgbImage, imageops::FilterType};
+
+use crate::fbimage::{self, Geometry};
+
+const SCALE: u32 = 3;
+const CORNER_RADIUS: f32 = 15.0;
+/// Distance from the edge at which the vignette becomes fully opaque
+const VIGNETTE_INSET: f32 = 10.0;
+const VIGNETTE_BLUR: f32 = 10.0;
+
+/// Coverage of a rounded rectangle inset by `inset` pixels on each side.
+fn rounded_rect(width: u32, height: u32, inset: f32, radius: f32, x: u32, y: u32) -> bool {
+    let (x, y) = (x as f32 + 0.5, y as f32 + 0.5);
+    let (left, top) = (inset, inset);
+    let (right, bottom) = (width as f32 - inset, height as f32 - inset);
+    if x < left || x > right || y < top || y > bottom {
+        return false;
+    }
+    let dx = (left + radius - x).max(x - (right - radius)).max(0.0);
+    let dy = (top + radius - y).max(y - (bottom - radius)).max(0.0);
+    dx * dx + dy * dy <= radius * radius
+}
+
+/// The alpha mask for the vignette: half-transparent towards the edges, fully
+/// transparent outside the rounded corners, and blurred.
+fn vignette_mask(width: u32, height: u32) -> GrayImage {
+    let mask = GrayImage::from_fn(width, height, |x, y| {
+        if rounded_rect(width, height, VIGNETTE_INSET, CORNER_RADIUS, x, y) {
+            Luma([255])
+        } else if rounded_rect(width, height, 0.0, CORNER_RADIUS, x, y) {
+            Luma([128])
+        } else {
+            Luma([0])
+        }
+    });
+    image::imageops::blur(&mask, VIGNETTE_BLUR)
+}
+
+/// Scale the image up 3x, apply a rounded-corner vignette, and center it on a
+/// black screen of the given size.
+pub fn render(image: &DynamicImage, width: u32, height: u32) -> RgbImage {
+    let scaled = image
+        .resize_exact(
+            image.width() * SCALE,
+            image.height() * SCALE,
+            FilterType::Nearest,
+        )
+        .to_rgb8();
+
+    let mask = vignette_mask(scaled.width(), scaled.height());
+    let mut vignetted = scaled;
+    for (pixel, alpha) in vignetted.pixels_mut().zip(mask.pixels()) {
+        for channel in &mut pixel.0 {
+            *channel = (*channel as u32 * alpha.0[0] as u32 / 255) as u8;
+        }
+    }
+
+    let mut screen = RgbImage::new(width, height);
+    let x = (width as i64 - vignetted.width() as i64) / 2;
+    let y = (height as i64 - vignetted.height() as i64) / 2;
+    image::imageops::overlay(&mut screen, &vignetted, x, y);
+    screen
+}
+
+/// Render a splash image and write it as a gzip'd raw framebuffer dump.
+pub fn render_to_file(
+    input: &Path,
+    output: &Path,
+    geometry: Geometry,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let image = fbimage::load(input)?;
+    let screen = render(&image, geometry.width, geometry.height);
+    let line_length = geometry.width as usize * geometry.format.bytes_per_pixel();
+    let raw = fbimage::to_raw(&screen, &geometry.format, line_length);
+
+    let mut encoder = GzEncoder::new(std::fs::File::create(output)?, Compression::best());
+    encoder.write_all(&raw)?;
+    encoder.finish()?;
+    Ok(())
+}
+
+/// `pi-frame render-splash <image> --fb-geometry <width>x<height>x<depth> [--output <file>]`
+pub fn render_splash_command(
+    args: impl IntoIterator<Item = String>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    const USAGE: &str = "Usage: pi-frame render-splash <image> --fb-geometry <width>x<height>x<depth> [--output <file>]";
+
+    let mut input = None;
+    let mut output = None;
+    let mut geometry = None;
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--fb-geometry" => geometry = Some(args.next().ok_or(USAGE)?.parse::<Geometry>()?),
+            "--output" | "-o" => output = Some(PathBuf::from(args.next().ok_or(USAGE)?)),
+            _ if input.is_none() && !arg.starts_with('-') => input = Some(PathBuf::from(arg)),
+            _ => return Err(USAGE.into()),
+        }
+    }
+    let (Some(input), Some(geometry)) = (input, geometry) else {
+        return Err(USAGE.into());
+    };
+    let output = output.unwrap_or_else(|| {
+        let mut output = input.clone().into_os_string();
+        output.push(".bin.gz");
+        output.into()
+    });
+
+    render_to_file(&input, &output, geometry)?;
+    eprintln!("Splash image saved to {}", output.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rounds_rect_corners() {
+        // Inside, and in the middle of each edge
+        assert!(rounded_rect(100, 50, 0.0, 10.0, 50, 25));
+        assert!(rounded_rect(100, 50, 0.0, 10.0, 0, 25));
+        assert!(rounded_rect(100, 50, 0.0, 10.0, 50, 49));
+        // The very corners are cut off
+        assert!(!rounded_rect(100, 50, 0.0, 10.0, 0, 0));
+        assert!(!rounded_rect(100, 50, 0.0, 10.0, 99, 49));
+        // The inset moves the edges in
+        assert!(!rounded_rect(100, 50, 5.0, 10.0, 2, 25));
+        assert!(rounded_rect(100, 50, 5.0, 10.0, 5, 25));
+    }
+
+    #[test]
+    fn vignettes_edges() {
+        let mask = vignette_mask(200, 100);
+        assert_eq!(mask.dimensions(), (200, 100));
+        let centre = mask.get_pixel(100, 50).0[0];
+        let edge = mask.get_pixel(100, 0).0[0];
+        let corner = mask.get_pixel(0, 0).0[0];
+        assert_eq!(centre, 255);
+        assert!(edge < centre && edge > 0, "edge is {edge}");
+        assert!(corner < edge, "corner is {corner}, edge is {edge}");
+    }
+
+    #[test]
+    fn renders_centred() {
+        let white = RgbImage::from_pixel(100, 60, image::Rgb([255, 255, 255]));
+        let screen = render(&DynamicImage::ImageRgb8(white), 400, 300);
+        assert_eq!(screen.dimensions(), (400, 300));
+        // Scaled up to 300x180, in the middle
+        assert_eq!(screen.get_pixel(200, 150).0, [255, 255, 255]);
+        assert_eq!(screen.get_pixel(40, 150).0, [0, 0, 0]);
+        assert_eq!(screen.get_pixel(200, 50).0, [0, 0, 0]);
+    }
+}
@@ -0,0 +1,516 @@
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    path::PathBuf,
+    rc::{Rc, Weak},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use gstreamer::prelude::*;
+use tracing::{error, info, warn};
+
+use crate::{
+    Config, InstantiatedSource, Layout, SourceEvent, audio::Mixer, build_pipeline, cells::Cells,
+    handle_bus_message, intercom::Intercom, metrics::PipelineMetrics,
+};
+
+/// Owns the running pipeline, and rebuilds it from scratch when something
+/// outside of a source fails (compositor, sink, fallbackswitch), or when frames
+/// stop reaching the display.
+///
+/// Individual RTSP sources are still restarted in place by `restart_source`.
+pub struct Supervisor {
+    state: Rc<RefCell<State>>,
+    stall_check: Option<glib::SourceId>,
+}
+
+/// Called with each of a source's events
+type Listener = Rc<dyn Fn(&SourceEvent)>;
+
+struct State {
+    config: Config,
+    /// The config from before a reload, until a pipeline has been built from
+    /// the new one, to go back to if it can't be
+    previous_config: Option<Config>,
+    /// The layout from the config file, for switching back to
+    default_layout: Layout,
+    width: usize,
+    height: usize,
+    running: Option<Running>,
+    /// When the last buffer reached the framebuffer sink
+    last_buffer: Arc<Mutex<Instant>>,
+    rebuild_pending: bool,
+    /// Set once we're shutting down, so pending rebuilds are abandoned
+    stopped: bool,
+    /// Set while the display is off and the pipeline has been stopped to save
+    /// power and bandwidth
+    paused: bool,
+    /// Called with every event from the running pipeline's sources
+    listeners: Vec<Listener>,
+    mixer: Mixer,
+    intercom: Intercom,
+}
+
+struct Running {
+    pipeline: gstreamer::Pipeline,
+    cells: Cells,
+    sources: HashMap<String, InstantiatedSource>,
+    metrics: Arc<PipelineMetrics>,
+    _bus_watch: gstreamer::bus::BusWatchGuard,
+}
+
+impl Supervisor {
+    /// Build and start the pipeline. Failure to build the initial pipeline is
+    /// fatal, as it almost certainly means the configuration is broken.
+    pub fn start(
+        config: Config,
+        width: usize,
+        height: usize,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let state = Rc::new(RefCell::new(State {
+            default_layout: config.display.layout,
+            mixer: Mixer::new(&config.audio),
+            intercom: Intercom::new(&config.intercom),
+            config,
+            previous_config: None,
+            width,
+            height,
+            running: None,
+            last_buffer: Arc::new(Mutex::new(Instant::now())),
+            rebuild_pending: false,
+            stopped: false,
+            paused: false,
+            listeners: vec![],
+        }));
+
+        start_pipeline(&state)?;
+
+        let weak = Rc::downgrade(&state);
+        let stall_check = glib::timeout_add_seconds_local(1, move || {
+            let Some(state) = weak.upgrade() else {
+                return glib::ControlFlow::Break;
+            };
+            check_stalled(&state);
+            glib::ControlFlow::Continue
+        });
+
+        Ok(Self {
+            state,
+            stall_check: Some(stall_check),
+        })
+    }
+
+    /// A handle for pausing and resuming the pipeline from elsewhere in the main
+    /// loop.
+    pub fn handle(&self) -> SupervisorHandle {
+        SupervisorHandle(Rc::downgrade(&self.state))
+    }
+
+    /// Stop the pipeline cleanly, giving elements a chance to see EOS before
+    /// everything is torn down.
+    pub fn shutdown(self) {
+        let running = {
+            let mut state = self.state.borrow_mut();
+            state.stopped = true;
+            state.running.take()
+        };
+        let Some(Running {
+            pipeline,
+            _bus_watch,
+            ..
+        }) = running
+        else {
+            return;
+        };
+
+        // EOS is a failure while running, so stop watching the bus first
+        drop(_bus_watch);
+
+        pipeline.send_event(gstreamer::event::Eos::new());
+        if let Some(bus) = pipeline.bus() {
+            bus.timed_pop_filtered(
+                gstreamer::ClockTime::from_seconds(2),
+                &[gstreamer::MessageType::Eos, gstreamer::MessageType::Error],
+            );
+        }
+        if let Err(e) = pipeline.set_state(gstreamer::State::Null) {
+            warn!("Error setting pipeline to null: {e:?}");
+        }
+    }
+}
+
+impl Drop for Supervisor {
+    fn drop(&mut self) {
+        if let Some(stall_check) = self.stall_check.take() {
+            stall_check.remove();
+        }
+        self.state.borrow_mut().stopped = true;
+        stop_pipeline(&self.state);
+    }
+}
+
+#[derive(Clone)]
+pub struct SupervisorHandle(Weak<RefCell<State>>);
+
+impl SupervisorHandle {
+    /// Call `listener` with every event from the sources, across rebuilds.
+    pub fn connect(&self, listener: impl Fn(&SourceEvent) + 'static) {
+        if let Some(state) = self.0.upgrade() {
+            state.borrow_mut().listeners.push(Rc::new(listener));
+        }
+    }
+
+    /// Stop the pipeline entirely, disconnecting from every camera, until
+    /// `resume` is called.
+    pub fn pause(&self) {
+        let Some(state) = self.0.upgrade() else {
+            return;
+        };
+        if std::mem::replace(&mut state.borrow_mut().paused, true) {
+            return;
+        }
+        info!("Pausing pipeline");
+        stop_pipeline(&state);
+    }
+
+    /// Rebuild the pipeline after `pause`.
+    pub fn resume(&self) {
+        let Some(state) = self.0.upgrade() else {
+            return;
+        };
+        if !std::mem::replace(&mut state.borrow_mut().paused, false) {
+            return;
+        }
+        info!("Resuming pipeline");
+        rebuild_soon(&state);
+    }
+
+    /// The running pipeline's cells, for focusing.
+    pub fn cells(&self) -> Option<Cells> {
+        let state = self.0.upgrade()?;
+        let state = state.borrow();
+        state.running.as_ref().map(|running| running.cells.clone())
+    }
+
+    /// What's heard, which outlives the pipeline.
+    pub fn mixer(&self) -> Option<Mixer> {
+        let state = self.0.upgrade()?;
+        let state = state.borrow();
+        Some(state.mixer.clone())
+    }
+
+    /// Who's talked to, which outlives the pipeline.
+    pub fn intercom(&self) -> Option<Intercom> {
+        let state = self.0.upgrade()?;
+        let state = state.borrow();
+        Some(state.intercom.clone())
+    }
+
+    /// The running pipeline and its sources, for inspecting and restarting them.
+    pub fn sources(&self) -> Option<(gstreamer::Pipeline, HashMap<String, InstantiatedSource>)> {
+        let state = self.0.upgrade()?;
+        let state = state.borrow();
+        let running = state.running.as_ref()?;
+        Some((running.pipeline.clone(), running.sources.clone()))
+    }
+
+    /// The running pipeline's metrics, in the Prometheus text format.
+    pub fn metrics(&self) -> Option<String> {
+        let state = self.0.upgrade()?;
+        let state = state.borrow();
+        let running = state.running.as_ref()?;
+        Some(running.metrics.render(&running.pipeline, &running.sources))
+    }
+
+    /// Write the running pipeline's graph to the `[graphs]` directory.
+    pub fn dump_graph(&self, reason: &str) -> Result<PathBuf, String> {
+        let state = self.0.upgrade().ok_or("pi-frame is shutting down")?;
+        dump_graph(&state.borrow(), reason).map_err(|e| e.to_string())
+    }
+
+    /// Switch to one of the display's named layouts, or back to `default`, and
+    /// rebuild the pipeline.
+    pub fn set_layout(&self, name: &str) -> Result<(), String> {
+        let Some(state) = self.0.upgrade() else {
+            return Ok(());
+        };
+        {
+            let mut state = state.borrow_mut();
+            let layout = match name {
+                "default" => state.default_layout,
+                _ => *state
+                    .config
+                    .display
+                    .layouts
+                    .get(name)
+                    .ok_or_else(|| format!("no layout named {name:?}"))?,
+            };
+            layout.check(state.config.sources.len())?;
+            state.config.display.layout = layout;
+        }
+        info!("Switching to layout: {name}");
+        rebuild_soon(&state);
+        Ok(())
+    }
+
+    /// Rebuild the pipeline from a new config.
+    pub fn replace_config(&self, config: Config) {
+        let Some(state) = self.0.upgrade() else {
+            return;
+        };
+        let changed = startup_sections_changed(&state.borrow().config, &config);
+        if !changed.is_empty() {
+            warn!(
+                "Restart pi-frame to apply changes to {}",
+                changed.join(", ")
+            );
+        }
+        {
+            let mut state = state.borrow_mut();
+            let old = state.apply_config(config);
+            // Keep the last config that worked, if it hasn't been replaced yet
+            state.previous_config.get_or_insert(old);
+        }
+        info!("Reloading config");
+        rebuild_soon(&state);
+    }
+}
+
+impl State {
+    /// Switch to a new config, returning the old one.
+    fn apply_config(&mut self, config: Config) -> Config {
+        self.default_layout = config.display.layout;
+        self.mixer.configure(&config.audio);
+        self.intercom.configure(&config.intercom);
+        std::mem::replace(&mut self.config, config)
+    }
+}
+
+/// The config sections that are only read when pi-frame starts, and differ.
+fn startup_sections_changed(old: &Config, new: &Config) -> Vec<&'static str> {
+    // They don't implement PartialEq, but their Debug output is as good
+    let changed = |old: &dyn std::fmt::Debug, new: &dyn std::fmt::Debug| {
+        format!("{old:?}") != format!("{new:?}")
+    };
+    let locale = |config: &Config| {
+        config
+            .display
+            .time
+            .as_ref()
+            .and_then(|time| time.locale().map(str::to_string))
+    };
+    let rtsp = |config: &Config| {
+        config
+            .restream
+            .as_ref()
+            .map(|restream| restream.rtsp.clone())
+    };
+    [
+        ("[display.time] locale", changed(&locale(old), &locale(new))),
+        ("[schedule]", changed(&old.schedule, &new.schedule)),
+        ("[mqtt]", changed(&old.mqtt, &new.mqtt)),
+        ("[api]", changed(&old.api, &new.api)),
+        ("[logging]", changed(&old.logging, &new.logging)),
+        ("[snapshots]", changed(&old.snapshots, &new.snapshots)),
+        ("[restream.rtsp]", changed(&rtsp(old), &rtsp(new))),
+        (
+            "[intercom.button]",
+            changed(&old.intercom.button, &new.intercom.button),
+        ),
+    ]
+    .into_iter()
+    .filter(|(_, changed)| *changed)
+    .map(|(section, _)| section)
+    .collect()
+}
+
+fn check_stalled(state: &Rc<RefCell<State>>) {
+    let stalled_for = {
+        let state = state.borrow();
+        if state.running.is_none() || state.rebuild_pending {
+            return;
+        }
+        let elapsed = state.last_buffer.lock().unwrap().elapsed();
+        if elapsed < Duration::from_secs(state.config.supervisor.stall_timeout) {
+            return;
+        }
+        elapsed
+    };
+
+    schedule_rebuild(
+        state,
+        format!(
+            "no frames reached the display for {}s",
+            stalled_for.as_secs()
+        ),
+    );
+}
+
+/// Write the running pipeline's graph, logging any failure.
+fn dump_graph(state: &State, reason: &str) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    let running = state.running.as_ref().ok_or("the pipeline isn't running")?;
+    state
+        .config
+        .graphs
+        .dump(&running.pipeline, reason)
+        .inspect_err(|e| warn!("Failed to dump the pipeline graph: {e}"))
+}
+
+/// Rebuild the pipeline from the main loop, outside of whatever callback noticed
+/// the failure.
+fn schedule_rebuild(state: &Rc<RefCell<State>>, reason: String) {
+    {
+        let mut state = state.borrow_mut();
+        if state.rebuild_pending {
+            return;
+        }
+        state.rebuild_pending = true;
+    }
+
+    error!("Pipeline failure, rebuilding: {reason}");
+    if state.borrow().config.graphs.on_error {
+        let _ = dump_graph(&state.borrow(), "error");
+    }
+
+    let state = state.clone();
+    glib::idle_add_local_once(move || rebuild(&state));
+}
+
+/// Rebuild the pipeline from the main loop, unless a rebuild is already
+/// pending, which will pick up any changes.
+fn rebuild_soon(state: &Rc<RefCell<State>>) {
+    if std::mem::replace(&mut state.borrow_mut().rebuild_pending, true) {
+        return;
+    }
+    let state = state.clone();
+    glib::idle_add_local_once(move || rebuild(&state));
+}
+
+fn rebuild(state: &Rc<RefCell<State>>) {
+    if state.borrow().stopped {
+        return;
+    }
+    if state.borrow().paused {
+        state.borrow_mut().rebuild_pending = false;
+        return;
+    }
+
+    // fbdevsink doesn't clear the framebuffer when it shuts down, so the last
+    // frame stays on screen until the new pipeline starts drawing.
+    stop_pipeline(state);
+
+    match start_pipeline(state) {
+        Ok(()) => {
+            let mut state = state.borrow_mut();
+            state.rebuild_pending = false;
+            state.previous_config = None;
+            info!("Pipeline rebuilt");
+        }
+        Err(e) => {
+            stop_pipeline(state);
+            let previous = state.borrow_mut().previous_config.take();
+            if let Some(previous) = previous {
+                error!(
+                    "Failed to build the reloaded config, going back to the previous one: {e:?}"
+                );
+                state.borrow_mut().apply_config(previous);
+                rebuild(state);
+                return;
+            }
+            let retry_delay = state.borrow().config.supervisor.retry_delay;
+            error!("Failed to rebuild pipeline, retrying in {retry_delay}s: {e:?}");
+            let state = state.clone();
+            glib::timeout_add_seconds_local_once(retry_delay as _, move || rebuild(&state));
+        }
+    }
+}
+
+fn stop_pipeline(state: &Rc<RefCell<State>>) {
+    let running = state.borrow_mut().running.take();
+    if let Some(running) = running
+        && let Err(e) = running.pipeline.set_state(gstreamer::State::Null)
+    {
+        warn!("Error setting pipeline to null: {e:?}");
+    }
+}
+
+fn start_pipeline(state: &Rc<RefCell<State>>) -> Result<(), Box<dyn std::error::Error>> {
+    let (frame_pipeline, last_buffer) = {
+        let state = state.borrow();
+        (
+            build_pipeline(
+                &state.config,
+                state.width,
+                state.height,
+                &state.mixer,
+                &state.intercom,
+            )?,
+            state.last_buffer.clone(),
+        )
+    };
+
+    *last_buffer.lock().unwrap() = Instant::now();
+    frame_pipeline
+        .sink_pad
+        .add_probe(gstreamer::PadProbeType::BUFFER, move |_pad, _info| {
+            *last_buffer.lock().unwrap() = Instant::now();
+            gstreamer::PadProbeReturn::Ok
+        });
+
+    let pipeline = frame_pipeline.pipeline;
+    let sources = frame_pipeline.sources;
+    let cells = frame_pipeline.cells;
+    let running_cells = cells.clone();
+    let running_sources = sources.clone();
+    let metrics = frame_pipeline.metrics;
+    let running_metrics = metrics.clone();
+    let weak: Weak<RefCell<State>> = Rc::downgrade(state);
+    let pipeline_clone = pipeline.clone();
+    let bus_watch = pipeline.bus().unwrap().add_watch_local(move |_, msg| {
+        match handle_bus_message(&pipeline_clone, &sources, &metrics, msg) {
+            Ok(Some(event)) => {
+                match &event {
+                    SourceEvent::Motion { source, .. } => cells.motion(source),
+                    SourceEvent::Restarted { source, reason } => {
+                        if let Some(state) = weak.upgrade() {
+                            let state = state.borrow();
+                            if state.config.graphs.on_restart {
+                                let _ = dump_graph(&state, &format!("{source}-{reason}"));
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+                // Listeners may pause or resume us, so don't hold on to the state
+                let listeners = match weak.upgrade() {
+                    Some(state) => state.borrow().listeners.clone(),
+                    None => vec![],
+                };
+                for listener in listeners {
+                    listener(&event);
+                }
+            }
+            Ok(None) => {}
+            Err(failure) => {
+                if let Some(state) = weak.upgrade() {
+                    schedule_rebuild(&state, failure.0);
+                }
+            }
+        }
+        glib::ControlFlow::Continue
+    })?;
+
+    // Keep track of the pipeline before starting it so a failed start gets torn down
+    state.borrow_mut().running = Some(Running {
+        pipeline: pipeline.clone(),
+        cells: running_cells,
+        sources: running_sources,
+        metrics: running_metrics,
+        _bus_watch: bus_watch,
+    });
+
+    pipeline.set_state(gstreamer::State::Playing)?;
+
+    Ok(())
+}
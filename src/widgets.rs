@@ -0,0 +1,282 @@
+//! On-screen widgets drawn on a transparent layer above the grid.
+
+use std::{
+    collections::HashMap,
+    io::BufRead,
+    path::{Path, PathBuf},
+    sync::{Arc, LazyLock, Mutex},
+};
+
+use gstreamer::{Bin, GhostPad, prelude::*};
+use serde::Deserialize;
+use tracing::error;
+
+use crate::{SourceState, clock, labels::LabelStyle};
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct Widget {
+    #[serde(flatten)]
+    kind: WidgetKind,
+    #[serde(default)]
+    style: LabelStyle,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum WidgetKind {
+    /// The time, as a strftime format, in the display clock's timezone and
+    /// locale
+    Clock {
+        #[serde(default = "default_clock_format")]
+        format: String,
+    },
+    /// The date, as a strftime format, like the time
+    Date {
+        #[serde(default = "default_date_format")]
+        format: String,
+    },
+    /// Text read from a file, or the last line written to a unix socket
+    Ticker {
+        file: Option<String>,
+        socket: Option<String>,
+        /// Scroll the text across the screen at this many pixels per second
+        scroll: Option<u32>,
+    },
+    /// Each source's description and whether its video is up
+    Status,
+    /// This machine's IP address
+    Ip,
+}
+
+fn default_clock_format() -> String {
+    "%H:%M".to_string()
+}
+
+fn default_date_format() -> String {
+    "%a %d %b".to_string()
+}
+
+impl Widget {
+    /// Resolve any paths relative to the config file's directory.
+    pub fn resolve_paths(&mut self, config_dir: &Path) {
+        if let WidgetKind::Ticker { file, socket, .. } = &mut self.kind {
+            for path in [file, socket].into_iter().flatten() {
+                *path = config_dir.join(&path).to_string_lossy().into_owned();
+            }
+        }
+    }
+
+    fn description(&self, index: usize) -> String {
+        let name = format!("widget_{index}");
+        let properties = self.style.properties();
+        match &self.kind {
+            WidgetKind::Ticker { scroll: Some(_), .. } => format!(
+                r#"textoverlay name={name} text="" {properties} halignment=left wrap-mode=none"#
+            ),
+            WidgetKind::Clock { .. }
+            | WidgetKind::Date { .. }
+            | WidgetKind::Ticker { .. }
+            | WidgetKind::Status
+            | WidgetKind::Ip => {
+                format!(r#"textoverlay name={name} text="" {properties}"#)
+            }
+        }
+    }
+}
+
+/// The latest text written to each ticker socket. Listeners outlive any one
+/// pipeline, so they're only started once per path.
+static TICKER_SOCKETS: LazyLock<Mutex<HashMap<PathBuf, Arc<Mutex<String>>>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+fn ticker_socket(path: &Path) -> std::io::Result<Arc<Mutex<String>>> {
+    let mut sockets = TICKER_SOCKETS.lock().unwrap();
+    if let Some(text) = sockets.get(path) {
+        return Ok(text.clone());
+    }
+
+    // Remove a stale socket left behind by a previous run
+    let _ = std::fs::remove_file(path);
+    let listener = std::os::unix::net::UnixListener::bind(path)?;
+    let text = Arc::new(Mutex::new(String::new()));
+    let text_clone = text.clone();
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(stream) = stream else {
+                continue;
+            };
+            for line in std::io::BufReader::new(stream).lines() {
+                let Ok(line) = line else {
+                    break;
+                };
+                *text_clone.lock().unwrap() = line;
+            }
+        }
+    });
+    sockets.insert(path.to_owned(), text.clone());
+    Ok(text)
+}
+
+/// The address of the interface that would be used to reach the internet. No
+/// packets are sent.
+fn local_ip() -> Option<std::net::IpAddr> {
+    let socket = std::net::UdpSocket::bind("0.0.0.0:0").ok()?;
+    socket.connect("8.8.8.8:80").ok()?;
+    Some(socket.local_addr().ok()?.ip())
+}
+
+fn status_text(sources: &[(String, Arc<Mutex<SourceState>>)]) -> String {
+    sources
+        .iter()
+        .map(|(description, state)| {
+            let status = if state.lock().unwrap().on_fallback {
+                "offline"
+            } else {
+                "OK"
+            };
+            format!("{description}: {status}")
+        })
+        .collect::<Vec<_>>()
+        .join("  |  ")
+}
+
+/// Create the widget layer: a transparent, full-screen video with each widget
+/// drawn on it.
+pub fn make_widget_layer(
+    widgets: &[Widget],
+    width: usize,
+    height: usize,
+) -> Result<gstreamer::Element, Box<dyn std::error::Error>> {
+    let overlays = widgets
+        .iter()
+        .enumerate()
+        .map(|(index, widget)| format!("! {}", widget.description(index)))
+        .collect::<Vec<_>>()
+        .join("\n");
+    let bin = Bin::with_name("widgets");
+    let pipeline = gstreamer::parse::launch(&format!(
+        r#"
+    videotestsrc pattern=solid-color foreground-color=0
+        ! video/x-raw,format=BGRA,width={width},height={height},framerate=10/1
+        {overlays}
+        ! queue max-size-buffers=1 leaky=downstream name=sink
+    "#
+    ))?;
+    bin.add(&pipeline)?;
+
+    let sink = pipeline.downcast::<gstreamer::Bin>().expect("not a bin");
+    let sink = sink.by_name("sink").expect("no sink");
+    let sink_pad = sink.static_pad("src").expect("static pad");
+
+    let ghost_pad = GhostPad::with_target(&sink_pad)?;
+    ghost_pad.set_active(true)?;
+    bin.add_pad(&ghost_pad)?;
+    Ok(bin.upcast())
+}
+
+/// Keep the widgets' text up to date from the main loop, for as long as the
+/// layer exists. Times are shown in the display clock's timezone, if any.
+pub fn update_widgets(
+    layer: &gstreamer::Element,
+    widgets: &[Widget],
+    width: usize,
+    time: Option<&clock::Clock>,
+    sources: Vec<(String, Arc<Mutex<SourceState>>)>,
+) {
+    let layer = layer.downcast_ref::<Bin>().expect("not a bin");
+    for (index, widget) in widgets.iter().enumerate() {
+        let Some(overlay) = layer.by_name(&format!("widget_{index}")) else {
+            continue;
+        };
+        let overlay = overlay.downgrade();
+        match &widget.kind {
+            WidgetKind::Clock { format } | WidgetKind::Date { format } => {
+                let format = format.clone();
+                let timezone = time.map_or_else(glib::TimeZone::local, clock::Clock::timezone);
+                update_every(overlay, 1, move |overlay| {
+                    overlay.set_property("text", clock::format_now(&format, &timezone));
+                });
+            }
+            WidgetKind::Ticker {
+                file,
+                socket,
+                scroll,
+            } => {
+                let socket = match socket.as_deref().map(|socket| ticker_socket(Path::new(socket))) {
+                    Some(Ok(socket)) => Some(socket),
+                    Some(Err(e)) => {
+                        error!("Failed to open ticker socket: {e:?}");
+                        None
+                    }
+                    None => None,
+                };
+                let file = file.clone();
+                let read = move || {
+                    if let Some(socket) = &socket {
+                        socket.lock().unwrap().clone()
+                    } else if let Some(file) = &file {
+                        std::fs::read_to_string(file)
+                            .map(|text| text.trim().replace('\n', "  "))
+                            .unwrap_or_default()
+                    } else {
+                        String::new()
+                    }
+                };
+                update_every(overlay.clone(), 1, move |overlay| {
+                    overlay.set_property("text", read());
+                });
+
+                if let Some(scroll) = *scroll {
+                    // Scroll from the right edge until the text is off the left
+                    let size = widget.style.size() as i32;
+                    let step = (scroll / 25).max(1) as i32;
+                    let mut offset = width as i32;
+                    glib::timeout_add_local(std::time::Duration::from_millis(40), move || {
+                        let Some(overlay) = overlay.upgrade() else {
+                            return glib::ControlFlow::Break;
+                        };
+                        let text_width =
+                            overlay.property::<String>("text").chars().count() as i32 * size;
+                        offset -= step;
+                        if offset < -text_width {
+                            offset = width as i32;
+                        }
+                        overlay.set_property("deltax", offset);
+                        glib::ControlFlow::Continue
+                    });
+                }
+            }
+            WidgetKind::Status => {
+                let sources = sources.clone();
+                update_every(overlay, 1, move |overlay| {
+                    overlay.set_property("text", status_text(&sources));
+                });
+            }
+            WidgetKind::Ip => {
+                update_every(overlay, 10, |overlay| {
+                    let ip = local_ip()
+                        .map(|ip| ip.to_string())
+                        .unwrap_or_else(|| "No network".to_string());
+                    overlay.set_property("text", ip);
+                });
+            }
+        }
+    }
+}
+
+fn update_every(
+    overlay: glib::WeakRef<gstreamer::Element>,
+    seconds: u32,
+    update: impl Fn(&gstreamer::Element) + 'static,
+) {
+    if let Some(overlay) = overlay.upgrade() {
+        update(&overlay);
+    }
+    glib::timeout_add_seconds_local(seconds, move || {
+        let Some(overlay) = overlay.upgrade() else {
+            return glib::ControlFlow::Break;
+        };
+        update(&overlay);
+        glib::ControlFlow::Continue
+    });
+}
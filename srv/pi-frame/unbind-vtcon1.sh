@@ -1,4 +1,4 @@
 #!/bin/bash
-gzip -d < /srv/pi-frame/cat-3.png.bin.gz > /dev/fb0
+/srv/pi-frame/pi-frame show /srv/pi-frame/cat-3.png.bin.gz --fb-geometry 1280x800x16
 sleep 1
 echo > /sys/class/vtconsole/vtcon1/bind